@@ -0,0 +1,84 @@
+//! Cost of updating and encoding the per process metrics in each collection, for a synthetic
+//! host with a few thousand processes.
+//!
+//! Run with `cargo bench`.
+
+#[allow(dead_code)]
+#[path = "../src/encoding.rs"]
+mod encoding;
+#[allow(dead_code)]
+#[path = "../src/labels.rs"]
+mod labels;
+#[allow(dead_code)]
+#[path = "../src/metrics.rs"]
+mod metrics;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kagiyama::prometheus::registry::Registry;
+use labels::Labels;
+use metrics::{EnergyMetric, EnergyUnit, ProcessMetrics, Sample};
+use std::collections::BTreeMap;
+
+const PROCESSES: u32 = 3000;
+
+/// Labels and counter increments of every process for one collection, as the collection loop
+/// produces from the process snapshots.
+fn synthetic_collection() -> Vec<(Labels, Sample)> {
+    (0..PROCESSES)
+        .map(|pid| {
+            let labels = Labels {
+                process_name: Some(format!("worker-{}", pid % 50)),
+                cmdline: Some(format!("/usr/bin/worker-{} --id {}", pid % 50, pid)),
+                pid: Some(pid.to_string()),
+                ppid: Some("1".to_string()),
+                uid: Some("1000".to_string()),
+                user: Some("app".to_string()),
+                ..Default::default()
+            };
+            let sample = Sample {
+                cpu_time: 0.01 * f64::from(pid % 7),
+                cpu_user_time: Some(0.007 * f64::from(pid % 7)),
+                cpu_kernel_time: Some(0.003 * f64::from(pid % 7)),
+                energy: 0.0001,
+                cpu_usage: Some(0.1),
+                memory_resident: 64 << 20,
+                memory_virtual: 512 << 20,
+                memory_swap: Some(0),
+                threads: Some(4),
+                open_file_descriptors: Some(32),
+                nice: Some(0),
+                start_time: Some(1_700_000_000),
+                disk_read: 4096,
+                voluntary_context_switches: Some(10),
+                involuntary_context_switches: Some(1),
+                minor_page_faults: Some(100),
+                major_page_faults: Some(0),
+                states: BTreeMap::from([("sleeping".to_string(), 1)]),
+                ..Default::default()
+            };
+            (labels, sample)
+        })
+        .collect()
+}
+
+fn collection(c: &mut Criterion) {
+    let collection = synthetic_collection();
+    let process_metrics = ProcessMetrics::new(false, EnergyUnit::WattHours, EnergyMetric::Energy);
+    let mut registry = Registry::default();
+    process_metrics.register(&mut registry);
+
+    c.bench_function("update 3000 processes", |b| {
+        b.iter(|| {
+            for (labels, sample) in &collection {
+                process_metrics.update(black_box(labels), black_box(sample));
+            }
+        })
+    });
+
+    c.bench_function("encode 3000 processes", |b| {
+        b.iter(|| encoding::encode_text(black_box(&registry)).unwrap())
+    });
+}
+
+criterion_group!(benches, collection);
+criterion_main!(benches);
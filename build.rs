@@ -0,0 +1,40 @@
+use std::{
+    env,
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Runs a command, returning its trimmed stdout if it succeeds.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let git_commit = command_output("git", &["rev-parse", "--short=12", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=YUUGI_GIT_COMMIT={}", git_commit);
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=YUUGI_RUSTC_VERSION={}", rustc_version);
+
+    // SOURCE_DATE_EPOCH allows reproducible builds to fix the timestamp
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .map(|epoch| UNIX_EPOCH + Duration::from_secs(epoch))
+        .unwrap_or_else(SystemTime::now);
+    println!(
+        "cargo:rustc-env=YUUGI_BUILD_TIMESTAMP={}",
+        humantime::format_rfc3339_seconds(build_time)
+    );
+}
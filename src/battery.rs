@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+fn read_value(path: &Path) -> Option<f64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// A battery in the Linux power supply class (e.g. `/sys/class/power_supply/BAT0`).
+struct Battery {
+    name: String,
+    path: PathBuf,
+}
+
+impl Battery {
+    /// Power being drawn from the battery in Watts, `None` if it is not discharging (e.g. on AC
+    /// power) or the power is not reported.
+    fn read_power(&self) -> Option<f64> {
+        let status = fs::read_to_string(self.path.join("status")).ok()?;
+        if status.trim() != "Discharging" {
+            return None;
+        }
+
+        // Not all batteries report power, in which case it is current (uA) times voltage (uV)
+        let power = match read_value(&self.path.join("power_now")) {
+            Some(uw) => uw / 1e6,
+            None => {
+                let current = read_value(&self.path.join("current_now"))?;
+                let voltage = read_value(&self.path.join("voltage_now"))?;
+                (current / 1e6) * (voltage / 1e6)
+            }
+        };
+
+        // Some batteries report 0 for a short time after being unplugged
+        (power > 0.0).then_some(power)
+    }
+}
+
+/// System power draw measured by the batteries of a laptop, which is only available when running
+/// on battery.
+pub(crate) struct Batteries {
+    batteries: Vec<Battery>,
+}
+
+impl Batteries {
+    /// Finds all batteries, returning `None` if there are none.
+    pub(crate) fn discover() -> Option<Self> {
+        let entries = match fs::read_dir(POWER_SUPPLY_PATH) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::info!("Power supply class not available: {}", e);
+                return None;
+            }
+        };
+
+        let mut batteries: Vec<Battery> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                fs::read_to_string(entry.path().join("type"))
+                    .is_ok_and(|supply_type| supply_type.trim() == "Battery")
+            })
+            .map(|entry| Battery {
+                name: entry.file_name().to_string_lossy().to_string(),
+                path: entry.path(),
+            })
+            .collect();
+        batteries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for battery in &batteries {
+            log::info!("Found battery {}", battery.name);
+        }
+
+        match batteries.is_empty() {
+            true => {
+                log::info!("No batteries found");
+                None
+            }
+            false => Some(Self { batteries }),
+        }
+    }
+
+    /// Power drawn from each battery in Watts, `None` for batteries that are not discharging.
+    pub(crate) fn read_power(&self) -> Vec<(&str, Option<f64>)> {
+        self.batteries
+            .iter()
+            .map(|battery| (battery.name.as_str(), battery.read_power()))
+            .collect()
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::{hash_map, HashMap};
+use sysinfo::Pid;
+
+struct Entry<T> {
+    start_time: u64,
+    generation: u64,
+    value: T,
+}
+
+/// Values read for a process when it is first seen, rather than every collection.
+///
+/// Entries are keyed by PID and start time, so a reused PID is read again, and processes that are
+/// not seen in a collection are forgotten at the end of it.
+pub(crate) struct ProcessCache<T> {
+    entries: HashMap<Pid, Entry<T>>,
+    generation: u64,
+}
+
+impl<T> Default for ProcessCache<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            generation: 0,
+        }
+    }
+}
+
+impl<T> ProcessCache<T> {
+    /// The value for a process, read with `read` if the process has not been seen before.
+    pub(crate) fn get_or_read(
+        &mut self,
+        pid: &Pid,
+        start_time: u64,
+        read: impl FnOnce() -> T,
+    ) -> &T {
+        let generation = self.generation;
+        let entry = match self.entries.entry(*pid) {
+            hash_map::Entry::Occupied(entry) if entry.get().start_time == start_time => {
+                entry.into_mut()
+            }
+            // A new process, or a reused PID
+            entry => entry
+                .insert_entry(Entry {
+                    start_time,
+                    generation,
+                    value: read(),
+                })
+                .into_mut(),
+        };
+        entry.generation = generation;
+        &entry.value
+    }
+
+    /// Forgets the processes that were not seen since the previous call.
+    pub(crate) fn end_collection(&mut self) {
+        let generation = self.generation;
+        self.entries
+            .retain(|_, entry| entry.generation == generation);
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_once_per_process() {
+        let mut cache = ProcessCache::default();
+        let mut reads = 0;
+        let mut get = |cache: &mut ProcessCache<u32>, pid: i32, start_time: u64| {
+            *cache.get_or_read(&Pid::from(pid), start_time, || {
+                reads += 1;
+                reads
+            })
+        };
+
+        assert_eq!(get(&mut cache, 1, 100), 1);
+        assert_eq!(get(&mut cache, 2, 100), 2);
+        cache.end_collection();
+        assert_eq!(get(&mut cache, 1, 100), 1);
+        // Reused PID
+        assert_eq!(get(&mut cache, 2, 200), 3);
+        cache.end_collection();
+
+        // PID 1 is forgotten once it is not seen in a collection
+        assert_eq!(get(&mut cache, 2, 200), 3);
+        cache.end_collection();
+        assert_eq!(get(&mut cache, 1, 100), 4);
+    }
+}
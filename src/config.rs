@@ -0,0 +1,119 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, Command};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+/// A value of an option in a config file.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    /// A section, in the order it is written in the file.
+    Table(IndexMap<String, Value>),
+}
+
+impl Value {
+    fn to_arg(&self) -> Result<String> {
+        match self {
+            Value::Bool(v) => Ok(v.to_string()),
+            Value::Integer(v) => Ok(v.to_string()),
+            Value::Float(v) => Ok(v.to_string()),
+            Value::String(v) => Ok(v.clone()),
+            Value::List(_) => Err(anyhow!("nested lists are not supported")),
+            Value::Table(_) => Err(anyhow!("nested sections are not supported")),
+        }
+    }
+}
+
+/// Loads a TOML (`.toml`) or YAML (`.yaml`/`.yml`) config file, whose keys are the names of
+/// command line options in snake case (e.g. `collection_interval`).
+///
+/// An option that takes `KEY=VALUE` values may also be given as a section, with an entry for
+/// each value.
+pub(crate) fn load(path: &Path) -> Result<HashMap<String, Value>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&contents)
+            .with_context(|| format!("Malformed config file {}", path.display())),
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents)
+            .with_context(|| format!("Malformed config file {}", path.display())),
+        _ => bail!(
+            "Unknown config file format for {} (expected .toml, .yaml or .yml)",
+            path.display()
+        ),
+    }
+}
+
+/// Converts config file options to command line arguments, skipping any option that was already
+/// given on the command line or via an environment variable (so that those take precedence over
+/// the config file).
+///
+/// Parsing the returned arguments along with the original ones validates the config file values
+/// in exactly the same way as command line arguments.
+pub(crate) fn to_args(
+    command: &Command,
+    matches: &ArgMatches,
+    config: HashMap<String, Value>,
+) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+
+    for (key, value) in config {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && arg.get_long().is_some())
+            .filter(|arg| arg.get_id() != "config")
+            .ok_or_else(|| anyhow!("Unknown option \"{}\" in config file", key))?;
+
+        if matches!(
+            matches.value_source(&key),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            log::debug!("Ignoring \"{}\" in config file as it is already set", key);
+            continue;
+        }
+
+        let long = format!("--{}", arg.get_long().unwrap());
+
+        if !arg.get_action().takes_values() {
+            match value {
+                Value::Bool(true) => args.push(long),
+                Value::Bool(false) => {}
+                _ => bail!("Option \"{}\" in config file must be a boolean", key),
+            }
+            continue;
+        }
+
+        let values = match value {
+            Value::List(values) => values,
+            Value::Table(entries) => {
+                for (name, value) in entries {
+                    let value = value
+                        .to_arg()
+                        .with_context(|| format!("Invalid value for \"{}\" in config file", key))?;
+                    args.push(long.clone());
+                    args.push(format!("{}={}", name, value));
+                }
+                continue;
+            }
+            value => vec![value],
+        };
+
+        for value in values {
+            args.push(long.clone());
+            args.push(
+                value
+                    .to_arg()
+                    .with_context(|| format!("Invalid value for \"{}\" in config file", key))?,
+            );
+        }
+    }
+
+    Ok(args)
+}
@@ -0,0 +1,66 @@
+use anyhow::{Context, Result};
+use kagiyama::prometheus::{encoding::text::encode, registry::Registry};
+use std::collections::HashMap;
+
+pub(crate) const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+pub(crate) const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Converts the OpenMetrics encoding of a registry to the Prometheus text format (version 0.0.4),
+/// which is understood by older scrapers and the Pushgateway.
+///
+/// Samples are untouched, but metadata is renamed to match the sample names where OpenMetrics
+/// uses a suffix (`_total` for counters, `_info` for info metrics, which become gauges), and
+/// metadata the Prometheus text format does not support is removed.
+fn to_text_format(openmetrics: &str) -> String {
+    let types: HashMap<&str, &str> = openmetrics
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.strip_prefix("# TYPE ")?.splitn(2, ' ');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect();
+
+    let mut text = String::new();
+
+    for line in openmetrics.lines() {
+        if line == "# EOF" || line.starts_with("# UNIT ") {
+            continue;
+        }
+
+        let metadata = ["# HELP ", "# TYPE "]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, line.strip_prefix(prefix)?)));
+
+        match metadata {
+            Some((prefix, rest)) => {
+                let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (name, rest) = match types.get(name).copied() {
+                    Some("counter") => (format!("{}_total", name), rest),
+                    Some("info") if prefix == "# TYPE " => (format!("{}_info", name), "gauge"),
+                    Some("info") => (format!("{}_info", name), rest),
+                    _ => (name.to_string(), rest),
+                };
+                text.push_str(&format!("{}{} {}\n", prefix, name, rest));
+            }
+            None => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+
+    text
+}
+
+/// Encodes the current values of all metrics in a registry in the OpenMetrics text format.
+pub(crate) fn encode_openmetrics(registry: &Registry) -> Result<String> {
+    let mut buffer = String::new();
+    encode(&mut buffer, registry).context("Failed to encode metrics")?;
+    Ok(buffer)
+}
+
+/// Encodes the current values of all metrics in a registry in the Prometheus text format.
+pub(crate) fn encode_text(registry: &Registry) -> Result<String> {
+    encode_openmetrics(registry).map(|openmetrics| to_text_format(&openmetrics))
+}
@@ -0,0 +1,49 @@
+use crate::labels::HostLabels;
+use std::time::Duration;
+
+/// Energy consumed since the previous reading in Joules.
+pub(crate) struct EnergyReading {
+    /// Energy consumed by the CPU, which is apportioned between processes
+    pub(crate) cpu: f64,
+    /// Energy consumed by each measured domain (which may overlap, e.g. a CPU package and the
+    /// cores within it), labelled by domain
+    pub(crate) domains: Vec<(HostLabels, f64)>,
+    /// Energy consumed by the CPU in each socket (physical package), if measured per socket, which
+    /// sums to `cpu`
+    pub(crate) sockets: Vec<(usize, f64)>,
+}
+
+/// A source of measured CPU energy consumption.
+pub(crate) trait EnergyMeter {
+    /// Name of the source, as reported in the cpu info metric.
+    fn name(&self) -> &'static str;
+
+    /// Description of what is measured, for the help text of the host energy metric.
+    fn description(&self) -> &'static str;
+
+    fn read_energy(&mut self) -> EnergyReading;
+
+    /// Socket (physical package) containing a logical CPU, if energy is measured per socket.
+    #[allow(unused_variables)]
+    fn cpu_socket(&self, cpu: usize) -> Option<usize> {
+        None
+    }
+}
+
+/// Finds a way of measuring CPU energy on this platform, returning `None` if there is none (in
+/// which case energy is estimated from CPU time).
+#[allow(unused_variables)]
+pub(crate) fn discover(collection_interval: Duration) -> Option<Box<dyn EnergyMeter>> {
+    // Arm servers do not have RAPL, but some expose CPU power via hwmon
+    #[cfg(target_os = "linux")]
+    return crate::rapl::Rapl::discover()
+        .map(|m| Box::new(m) as Box<dyn EnergyMeter>)
+        .or_else(|| crate::hwmon::Hwmon::discover().map(|m| Box::new(m) as Box<dyn EnergyMeter>));
+
+    #[cfg(target_os = "macos")]
+    return crate::powermetrics::Powermetrics::start(collection_interval)
+        .map(|m| Box::new(m) as Box<dyn EnergyMeter>);
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    None
+}
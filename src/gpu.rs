@@ -0,0 +1,195 @@
+use crate::labels::HostLabels;
+use kagiyama::prometheus::{
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::{Registry, Unit},
+};
+use nvml_wrapper::{error::NvmlError, Nvml};
+use std::{collections::HashMap, sync::atomic::AtomicU64, time::Instant};
+use sysinfo::{Pid, PidExt};
+
+type GpuFamily<M> = Family<HostLabels, M>;
+
+struct Gpu {
+    index: u32,
+    /// Labels identifying the GPU (index and UUID)
+    labels: HostLabels,
+    /// Total energy counter in millijoules, `None` if not supported (older than Volta)
+    last_energy_mj: Option<u64>,
+    /// Timestamp (in microseconds) of the most recent per process utilisation sample
+    last_sample_timestamp: Option<u64>,
+}
+
+/// GPU power, energy and utilisation of NVIDIA GPUs via NVML.
+pub(crate) struct Gpus {
+    nvml: Nvml,
+    gpus: Vec<Gpu>,
+    last_collection: Instant,
+
+    power: GpuFamily<Gauge<f64, AtomicU64>>,
+    energy: GpuFamily<Counter<f64, AtomicU64>>,
+    utilization: GpuFamily<Gauge<f64, AtomicU64>>,
+}
+
+impl Gpus {
+    /// Finds all NVIDIA GPUs, returning `None` if NVML is not available (i.e. there is no NVIDIA
+    /// driver) or there are no GPUs.
+    pub(crate) fn discover() -> Option<Self> {
+        let nvml = match Nvml::init() {
+            Ok(nvml) => nvml,
+            Err(e) => {
+                log::info!("NVML not available: {}", e);
+                return None;
+            }
+        };
+
+        let count = match nvml.device_count() {
+            Ok(count) => count,
+            Err(e) => {
+                log::warn!("Failed to count GPUs, err: {}", e);
+                return None;
+            }
+        };
+
+        let mut gpus = Vec::new();
+        for index in 0..count {
+            let device = match nvml.device_by_index(index) {
+                Ok(device) => device,
+                Err(e) => {
+                    log::warn!("Failed to open GPU {}, err: {}", index, e);
+                    continue;
+                }
+            };
+
+            let uuid = device.uuid().unwrap_or_else(|_| "unknown".to_string());
+            log::info!("Found GPU {} ({})", index, uuid);
+
+            gpus.push(Gpu {
+                index,
+                labels: HostLabels(vec![
+                    ("gpu".to_string(), index.to_string()),
+                    ("uuid".to_string(), uuid),
+                ]),
+                last_energy_mj: device.total_energy_consumption().ok(),
+                last_sample_timestamp: None,
+            });
+        }
+
+        match gpus.is_empty() {
+            true => None,
+            false => Some(Self {
+                nvml,
+                gpus,
+                last_collection: Instant::now(),
+                power: Default::default(),
+                energy: Default::default(),
+                utilization: Default::default(),
+            }),
+        }
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "gpu_power",
+            "Power draw of GPU",
+            Unit::Other("watts".to_string()),
+            self.power.clone(),
+        );
+        registry.register_with_unit(
+            "gpu_energy",
+            "Total energy consumed by GPU",
+            Unit::Joules,
+            self.energy.clone(),
+        );
+        registry.register(
+            "gpu_utilization_ratio",
+            "Fraction of time over the last sample period that the GPU was executing kernels",
+            self.utilization.clone(),
+        );
+    }
+
+    /// Updates the GPU metrics, returning the energy in Joules used by each process on the GPUs
+    /// since the last collection.
+    ///
+    /// The energy of each GPU is apportioned between processes by their share of its SM
+    /// utilisation, where the driver reports per process utilisation.
+    pub(crate) fn collect(&mut self) -> HashMap<Pid, f64> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_collection).as_secs_f64();
+        self.last_collection = now;
+
+        let mut process_energy = HashMap::new();
+
+        for gpu in self.gpus.iter_mut() {
+            let device = match self.nvml.device_by_index(gpu.index) {
+                Ok(device) => device,
+                Err(e) => {
+                    log::warn!("Failed to open GPU {}, err: {}", gpu.index, e);
+                    continue;
+                }
+            };
+
+            let power = match device.power_usage() {
+                Ok(mw) => Some(mw as f64 / 1000.0),
+                Err(e) => {
+                    log::debug!("Failed to read power of GPU {}, err: {}", gpu.index, e);
+                    None
+                }
+            };
+            if let Some(power) = power {
+                self.power.get_or_create(&gpu.labels).set(power);
+            }
+
+            if let Ok(utilization) = device.utilization_rates() {
+                self.utilization
+                    .get_or_create(&gpu.labels)
+                    .set(utilization.gpu as f64 / 100.0);
+            }
+
+            // Prefer the energy counter, falling back to integrating the instantaneous power
+            let energy = match (gpu.last_energy_mj, device.total_energy_consumption()) {
+                (Some(last), Ok(current)) => {
+                    gpu.last_energy_mj = Some(current);
+                    Some(current.saturating_sub(last) as f64 / 1000.0)
+                }
+                _ => power.map(|power| power * elapsed),
+            };
+            let energy = match energy {
+                Some(energy) => energy,
+                None => continue,
+            };
+            self.energy.get_or_create(&gpu.labels).inc_by(energy);
+
+            // NotFound means there are no samples newer than the timestamp
+            let samples = match device.process_utilization_stats(gpu.last_sample_timestamp) {
+                Ok(samples) => samples,
+                Err(NvmlError::NotFound) => Vec::new(),
+                Err(e) => {
+                    log::debug!(
+                        "Per process utilisation not available for GPU {}, err: {}",
+                        gpu.index,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let mut utilization = HashMap::<Pid, u64>::new();
+            for sample in &samples {
+                *utilization.entry(Pid::from_u32(sample.pid)).or_default() += sample.sm_util as u64;
+                gpu.last_sample_timestamp =
+                    Some(gpu.last_sample_timestamp.unwrap_or(0).max(sample.timestamp));
+            }
+
+            let total_utilization: u64 = utilization.values().sum();
+            if total_utilization == 0 {
+                continue;
+            }
+            for (pid, utilization) in utilization {
+                *process_energy.entry(pid).or_default() +=
+                    energy * (utilization as f64 / total_utilization as f64);
+            }
+        }
+
+        process_energy
+    }
+}
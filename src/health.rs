@@ -0,0 +1,60 @@
+use kagiyama::prometheus::{
+    encoding::{EncodeMetric, MetricEncoder},
+    metrics::MetricType,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Whether collection is keeping up, as a gauge that is 1 if the last collection completed within
+/// the staleness threshold and 0 otherwise.
+///
+/// This is evaluated when the metric is encoded, so that it drops to 0 if the collection loop
+/// stalls or keeps failing, which a value set by the loop itself could not do.
+#[derive(Debug, Clone)]
+pub(crate) struct CollectorUp {
+    last_collection: Arc<Mutex<Option<Instant>>>,
+    staleness_threshold: Duration,
+}
+
+impl CollectorUp {
+    pub(crate) fn new(staleness_threshold: Duration) -> Self {
+        Self {
+            last_collection: Default::default(),
+            staleness_threshold,
+        }
+    }
+
+    /// Records a successful collection.
+    pub(crate) fn collected(&self) {
+        *self.last_collection.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn is_up(&self) -> bool {
+        self.last_collection
+            .lock()
+            .unwrap()
+            .is_some_and(|last| last.elapsed() <= self.staleness_threshold)
+    }
+
+    /// Whether yuugi should be considered alive, which unlike [`Self::is_up`] is also the case
+    /// before the first collection (e.g. while it is delayed by jitter), as that is covered by
+    /// readiness.
+    pub(crate) fn is_alive(&self) -> bool {
+        self.last_collection
+            .lock()
+            .unwrap()
+            .is_none_or(|last| last.elapsed() <= self.staleness_threshold)
+    }
+}
+
+impl EncodeMetric for CollectorUp {
+    fn encode(&self, mut encoder: MetricEncoder<'_, '_>) -> Result<(), std::fmt::Error> {
+        encoder.encode_gauge(&(self.is_up() as i64))
+    }
+
+    fn metric_type(&self) -> MetricType {
+        MetricType::Gauge
+    }
+}
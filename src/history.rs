@@ -0,0 +1,233 @@
+use crate::{labels::Labels, metrics::ProcessMetrics, snapshot::ProcessSnapshot};
+use std::collections::{HashMap, HashSet};
+use sysinfo::Pid;
+
+/// Number of consecutive collections a process can be missing from before it is treated as
+/// having exited.
+const MISSED_COLLECTIONS: u32 = 1;
+
+/// State of a process at the last collection it was collected in.
+pub(crate) struct PreviousProcess {
+    pub(crate) snapshot: ProcessSnapshot,
+    pub(crate) labels: Labels,
+    /// Number of consecutive collections since, that the process was not collected in
+    pub(crate) missed: u32,
+}
+
+/// Processes and series of the previous collections, which counters are incremented relative to.
+#[derive(Default)]
+pub(crate) struct History {
+    processes: HashMap<Pid, PreviousProcess>,
+    /// Series that were exported in the previous collection, or kept for a missed process
+    labels: HashSet<Labels>,
+}
+
+impl History {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+
+    pub(crate) fn get(&self, pid: &Pid) -> Option<&PreviousProcess> {
+        self.processes.get(pid)
+    }
+
+    /// Forgets a PID that has been reused by a new process, which the kernel does once a process
+    /// has exited, so that the new process is not counted relative to the old one.
+    ///
+    /// The series of the old process is removed, unless it is aggregated with other processes.
+    pub(crate) fn check_reused(
+        &mut self,
+        process_metrics: &ProcessMetrics,
+        pid: &Pid,
+        start_time: u64,
+    ) {
+        if let Some(previous) = self.processes.get(pid) {
+            if previous.snapshot.start_time != start_time {
+                log::debug!("PID {} has been reused", pid);
+                if previous.labels.pid.is_some() {
+                    process_metrics.remove(&previous.labels);
+                }
+                self.processes.remove(pid);
+            }
+        }
+    }
+
+    /// Remembers the processes of a collection for the next, and removes the series of processes
+    /// that have exited.
+    ///
+    /// A process can be missed by a collection without having exited (e.g. if reading it from
+    /// /proc failed), so it is remembered for a while in case it reappears. Otherwise all of its
+    /// CPU time would be counted again, and its series would be reset.
+    pub(crate) fn update(
+        &mut self,
+        process_metrics: &ProcessMetrics,
+        samples: Vec<(Pid, Labels, ProcessSnapshot)>,
+        exported: impl IntoIterator<Item = Labels>,
+    ) {
+        let collected: HashSet<Pid> = samples.iter().map(|(pid, _, _)| *pid).collect();
+        let missed_processes: Vec<(Pid, PreviousProcess)> = self
+            .processes
+            .drain()
+            .filter(|(pid, previous)| {
+                !collected.contains(pid) && previous.missed < MISSED_COLLECTIONS
+            })
+            .map(|(pid, previous)| {
+                let missed = previous.missed + 1;
+                (pid, PreviousProcess { missed, ..previous })
+            })
+            .collect();
+
+        let current_labels: HashSet<Labels> = exported
+            .into_iter()
+            .chain(
+                missed_processes
+                    .iter()
+                    .map(|(_, previous)| previous.labels.clone()),
+            )
+            .collect();
+        for labels in self.labels.difference(&current_labels) {
+            log::debug!("Removing series for exited process {:?}", labels);
+            process_metrics.remove(labels);
+        }
+        self.labels = current_labels;
+
+        self.processes = samples
+            .into_iter()
+            .map(|(pid, labels, snapshot)| {
+                let previous = PreviousProcess {
+                    snapshot,
+                    labels,
+                    missed: 0,
+                };
+                (pid, previous)
+            })
+            .chain(missed_processes)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{EnergyMetric, EnergyUnit, Sample};
+
+    fn labels(pid: u32) -> Labels {
+        Labels {
+            process_name: Some("test".to_string()),
+            pid: Some(pid.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn snapshot(start_time: u64, cpu_time: f64) -> ProcessSnapshot {
+        ProcessSnapshot {
+            start_time,
+            cpu_time,
+            ..Default::default()
+        }
+    }
+
+    fn process_metrics() -> ProcessMetrics {
+        ProcessMetrics::new(false, EnergyUnit::WattHours, EnergyMetric::Energy)
+    }
+
+    /// Exports the given processes, as the collection loop does.
+    fn collect(
+        history: &mut History,
+        process_metrics: &ProcessMetrics,
+        samples: Vec<(Pid, Labels, ProcessSnapshot)>,
+    ) {
+        for (pid, labels, snapshot) in &samples {
+            history.check_reused(process_metrics, pid, snapshot.start_time);
+            let previous = history.get(pid).map_or(0.0, |p| p.snapshot.cpu_time);
+            let sample = Sample {
+                cpu_time: (snapshot.cpu_time - previous).max(0.0),
+                ..Default::default()
+            };
+            process_metrics.update(labels, &sample);
+        }
+        let exported: Vec<Labels> = samples.iter().map(|(_, l, _)| l.clone()).collect();
+        history.update(process_metrics, samples, exported);
+    }
+
+    fn series(process_metrics: &ProcessMetrics) -> Vec<(Labels, f64)> {
+        process_metrics
+            .snapshot()
+            .into_iter()
+            .map(|(labels, sample)| (labels, sample.cpu_time))
+            .collect()
+    }
+
+    #[test]
+    fn missed_process_keeps_series_until_threshold() {
+        let process_metrics = process_metrics();
+        let mut history = History::default();
+        let pid = Pid::from(1);
+
+        collect(
+            &mut history,
+            &process_metrics,
+            vec![(pid, labels(1), snapshot(100, 2.0))],
+        );
+        assert_eq!(series(&process_metrics), vec![(labels(1), 2.0)]);
+
+        // Missing from one collection
+        collect(&mut history, &process_metrics, Vec::new());
+        assert_eq!(series(&process_metrics), vec![(labels(1), 2.0)]);
+        assert_eq!(history.get(&pid).unwrap().missed, 1);
+
+        // Missing from more than the threshold
+        collect(&mut history, &process_metrics, Vec::new());
+        assert!(series(&process_metrics).is_empty());
+        assert!(history.get(&pid).is_none());
+    }
+
+    #[test]
+    fn reused_pid_starts_new_series() {
+        let process_metrics = process_metrics();
+        let mut history = History::default();
+        let pid = Pid::from(1);
+        let new_process = Labels {
+            process_name: Some("other".to_string()),
+            ..labels(1)
+        };
+
+        collect(
+            &mut history,
+            &process_metrics,
+            vec![(pid, labels(1), snapshot(100, 5.0))],
+        );
+        // Less CPU time than the old process, which would otherwise be a negative interval
+        collect(
+            &mut history,
+            &process_metrics,
+            vec![(pid, new_process.clone(), snapshot(200, 1.0))],
+        );
+        assert_eq!(series(&process_metrics), vec![(new_process, 1.0)]);
+        assert_eq!(history.get(&pid).unwrap().snapshot.start_time, 200);
+    }
+
+    #[test]
+    fn reused_pid_keeps_aggregated_series() {
+        let process_metrics = process_metrics();
+        let mut history = History::default();
+        let pid = Pid::from(1);
+        let aggregated = Labels {
+            process_name: Some("test".to_string()),
+            ..Default::default()
+        };
+
+        collect(
+            &mut history,
+            &process_metrics,
+            vec![(pid, aggregated.clone(), snapshot(100, 5.0))],
+        );
+        collect(
+            &mut history,
+            &process_metrics,
+            vec![(pid, aggregated.clone(), snapshot(200, 1.0))],
+        );
+        // The whole CPU time of the new process is added, and the series never decreases
+        assert_eq!(series(&process_metrics), vec![(aggregated, 6.0)]);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{
+    energy::{EnergyMeter, EnergyReading},
+    labels::HostLabels,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+const HWMON_PATH: &str = "/sys/class/hwmon";
+
+fn read_u64(path: &Path) -> io::Result<u64> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Whether a sensor label names the power of the CPU (e.g. "CPU power" on Ampere Altra, or
+/// "CPU Power Socket 0" on NVIDIA Grace), rather than of other parts of the SoC or board.
+fn is_cpu_label(label: &str) -> bool {
+    label
+        .to_lowercase()
+        .split_whitespace()
+        .any(|word| word == "cpu")
+}
+
+enum Reading {
+    /// Cumulative energy counter, in microjoules
+    Energy { last_uj: u64 },
+    /// Instantaneous power, in microwatts, integrated over the time between readings
+    Power { last_read: Instant },
+}
+
+struct Sensor {
+    /// Name of the chip (e.g. "smpro"), as multiple chips may have sensors with the same label
+    chip: String,
+    label: String,
+    path: PathBuf,
+    reading: Reading,
+    /// Whether a read has failed, so that a sensor that cannot be read is only warned about once
+    read_failed: bool,
+}
+
+impl Sensor {
+    /// Energy consumed since the last read in Joules.
+    fn read_delta(&mut self) -> io::Result<f64> {
+        let value = read_u64(&self.path)?;
+
+        match &mut self.reading {
+            Reading::Energy { last_uj } => {
+                // The counters are 64 bit, so a decrease means the driver was reloaded
+                let delta = value.saturating_sub(*last_uj);
+                *last_uj = value;
+                Ok((delta as f64) / 1_000_000.0)
+            }
+            Reading::Power { last_read } => {
+                let now = Instant::now();
+                let elapsed = now.duration_since(*last_read).as_secs_f64();
+                *last_read = now;
+                Ok((value as f64) / 1_000_000.0 * elapsed)
+            }
+        }
+    }
+}
+
+/// Energy measurement via CPU energy or power sensors exposed by hwmon drivers, as on some Arm
+/// servers (e.g. Ampere Altra and NVIDIA Grace) that do not have RAPL.
+///
+/// Energy counters (`energyN_input`) of a chip are used in preference to power
+/// (`powerN_input` or `powerN_average`), which is sampled at each reading so is less accurate.
+pub(crate) struct Hwmon {
+    sensors: Vec<Sensor>,
+}
+
+impl Hwmon {
+    /// Finds the sensors labelled as measuring the CPU, returning `None` if there are none.
+    pub(crate) fn discover() -> Option<Self> {
+        let entries = match fs::read_dir(HWMON_PATH) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::info!("hwmon not available: {}", e);
+                return None;
+            }
+        };
+
+        let mut sensors = Vec::new();
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let chip = match fs::read_to_string(path.join("name")) {
+                Ok(name) => name.trim().to_string(),
+                Err(_) => continue,
+            };
+
+            let mut files: Vec<String> = match fs::read_dir(&path) {
+                Ok(files) => files
+                    .flatten()
+                    .filter_map(|file| file.file_name().into_string().ok())
+                    .collect(),
+                Err(_) => continue,
+            };
+            files.sort();
+
+            let sensor = |file: &str, suffix: &str| -> Option<(String, PathBuf)> {
+                let channel = file.strip_suffix(suffix)?;
+                let label = fs::read_to_string(path.join(format!("{}_label", channel))).ok()?;
+                let label = label.trim();
+                is_cpu_label(label).then(|| (label.to_string(), path.join(file)))
+            };
+
+            let energy: Vec<_> = files
+                .iter()
+                .filter(|file| file.starts_with("energy"))
+                .filter_map(|file| sensor(file, "_input"))
+                .collect();
+            let chip_sensors: Vec<_> = match energy.is_empty() {
+                false => energy
+                    .into_iter()
+                    .filter_map(|(label, path)| {
+                        let last_uj = read_u64(&path).ok()?;
+                        Some((label, path, Reading::Energy { last_uj }))
+                    })
+                    .collect(),
+                true => files
+                    .iter()
+                    .filter(|file| file.starts_with("power"))
+                    .filter_map(|file| match file.strip_suffix("_average") {
+                        // Channels with both are only read once
+                        Some(channel) if files.contains(&format!("{}_input", channel)) => None,
+                        Some(_) => sensor(file, "_average"),
+                        None => sensor(file, "_input"),
+                    })
+                    .filter(|(_, path)| read_u64(path).is_ok())
+                    .map(|(label, path)| {
+                        let last_read = Instant::now();
+                        (label, path, Reading::Power { last_read })
+                    })
+                    .collect(),
+            };
+
+            for (label, path, reading) in chip_sensors {
+                log::info!(
+                    "Found hwmon sensor \"{}\" of {} ({})",
+                    label,
+                    chip,
+                    path.display()
+                );
+                sensors.push(Sensor {
+                    chip: chip.clone(),
+                    label,
+                    path,
+                    reading,
+                    read_failed: false,
+                });
+            }
+        }
+
+        if sensors.is_empty() {
+            log::info!("No hwmon sensors measure the CPU");
+            return None;
+        }
+        Some(Self { sensors })
+    }
+}
+
+impl EnergyMeter for Hwmon {
+    fn name(&self) -> &'static str {
+        "hwmon"
+    }
+
+    fn description(&self) -> &'static str {
+        "Total energy consumed by the CPU as measured by each hwmon sensor"
+    }
+
+    /// CPU energy is the total of all sensors.
+    fn read_energy(&mut self) -> EnergyReading {
+        let mut reading = EnergyReading {
+            cpu: 0.0,
+            domains: Vec::new(),
+            sockets: Vec::new(),
+        };
+
+        for sensor in self.sensors.iter_mut() {
+            match sensor.read_delta() {
+                Ok(energy) => {
+                    reading.cpu += energy;
+                    let labels = HostLabels(vec![
+                        ("domain".to_string(), sensor.label.clone()),
+                        ("chip".to_string(), sensor.chip.clone()),
+                    ]);
+                    reading.domains.push((labels, energy));
+                }
+                Err(e) if sensor.read_failed => {
+                    log::debug!(
+                        "Failed to read hwmon sensor {}, err: {}",
+                        sensor.path.display(),
+                        e
+                    );
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to read hwmon sensor {}, err: {}",
+                        sensor.path.display(),
+                        e
+                    );
+                    sensor.read_failed = true;
+                }
+            }
+        }
+
+        reading
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(label: &str, path: PathBuf) -> Sensor {
+        Sensor {
+            chip: "smpro".to_string(),
+            label: label.to_string(),
+            path,
+            reading: Reading::Energy { last_uj: 1_000_000 },
+            read_failed: false,
+        }
+    }
+
+    #[test]
+    fn unreadable_sensor_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("yuugi-hwmon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let energy_path = dir.join("energy1_input");
+        fs::write(&energy_path, "3000000\n").unwrap();
+
+        let mut hwmon = Hwmon {
+            sensors: vec![
+                sensor("CPU energy", energy_path),
+                sensor("IO energy", dir.join("energy2_input")),
+            ],
+        };
+        let first = hwmon.read_energy();
+        let second = hwmon.read_energy();
+        fs::remove_dir_all(&dir).ok();
+
+        let labels = HostLabels(vec![
+            ("domain".to_string(), "CPU energy".to_string()),
+            ("chip".to_string(), "smpro".to_string()),
+        ]);
+        assert_eq!(first.cpu, 2.0);
+        assert_eq!(first.domains, vec![(labels.clone(), 2.0)]);
+        assert_eq!(second.domains, vec![(labels, 0.0)]);
+        // Only warned about the first time
+        assert!(!hwmon.sensors[0].read_failed);
+        assert!(hwmon.sensors[1].read_failed);
+    }
+}
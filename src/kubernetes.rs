@@ -0,0 +1,76 @@
+use std::env;
+
+/// A field of the pod metadata that can be exposed via the downward API.
+struct Field {
+    /// Environment variable the field is expected in
+    env: &'static str,
+    /// Prometheus label name, matching those used by kube-state-metrics
+    label: &'static str,
+    /// OpenTelemetry resource attribute name
+    attribute: &'static str,
+}
+
+/// Names of the Prometheus labels of every field.
+pub(crate) const LABEL_NAMES: [&str; 3] = [FIELDS[0].label, FIELDS[1].label, FIELDS[2].label];
+
+const FIELDS: [Field; 3] = [
+    Field {
+        env: "POD_NAME",
+        label: "pod",
+        attribute: "k8s.pod.name",
+    },
+    Field {
+        env: "POD_NAMESPACE",
+        label: "namespace",
+        attribute: "k8s.namespace.name",
+    },
+    Field {
+        env: "NODE_NAME",
+        label: "node",
+        attribute: "k8s.node.name",
+    },
+];
+
+/// Metadata of the Kubernetes pod yuugi is running in, read from environment variables set via
+/// the downward API.
+///
+/// Fields whose environment variable is not set (e.g. when not running in Kubernetes) are omitted.
+pub(crate) struct PodMetadata {
+    values: Vec<(&'static Field, String)>,
+}
+
+impl PodMetadata {
+    pub(crate) fn from_env() -> Self {
+        let values: Vec<_> = FIELDS
+            .iter()
+            .filter_map(|field| {
+                env::var(field.env)
+                    .ok()
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (field, value))
+            })
+            .collect();
+
+        for (field, value) in &values {
+            log::info!("Using {} \"{}\" (from {})", field.label, value, field.env);
+        }
+
+        Self { values }
+    }
+
+    /// Prometheus label names and values.
+    pub(crate) fn labels(&self) -> Vec<(&'static str, &str)> {
+        self.values
+            .iter()
+            .map(|(field, value)| (field.label, value.as_str()))
+            .collect()
+    }
+
+    /// OpenTelemetry resource attribute names and values.
+    pub(crate) fn attributes(&self) -> Vec<(&'static str, &str)> {
+        self.values
+            .iter()
+            .map(|(field, value)| (field.attribute, value.as_str()))
+            .collect()
+    }
+}
@@ -0,0 +1,313 @@
+use kagiyama::prometheus::encoding::{
+    EncodeLabelSet, EncodeLabelValue, LabelSetEncoder, LabelValueEncoder,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+};
+
+/// Labels identifying a process, or a group of processes when aggregating.
+///
+/// Fields that are `None` are omitted from the exported label set.
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Labels {
+    /// Name of the command line group the process belongs to.
+    pub(crate) group: Option<String>,
+    pub(crate) process_name: Option<String>,
+    pub(crate) cmdline: Option<String>,
+    pub(crate) pid: Option<String>,
+    /// Not set for processes without a parent (e.g. init).
+    /// Orphaned processes are reparented (usually to init, PID 1), which changes this label and so
+    /// starts a new series.
+    pub(crate) ppid: Option<String>,
+    pub(crate) uid: Option<String>,
+    pub(crate) user: Option<String>,
+    pub(crate) sched_policy: Option<String>,
+    pub(crate) cgroup: Option<String>,
+    pub(crate) container_id: Option<String>,
+    pub(crate) pod_uid: Option<String>,
+    pub(crate) systemd_unit: Option<String>,
+    /// Names and values of the labels from the environment variables of the process
+    /// (--label-from-env), only those the process has.
+    pub(crate) env: Vec<(String, String)>,
+}
+
+impl Labels {
+    /// Names of all labels, in the order they are exported.
+    pub(crate) const NAMES: [&'static str; 12] = [
+        "group",
+        "process_name",
+        "cmdline",
+        "pid",
+        "ppid",
+        "uid",
+        "user",
+        "sched_policy",
+        "cgroup",
+        "container_id",
+        "pod_uid",
+        "systemd_unit",
+    ];
+
+    /// Label names and values, excluding those that are not set.
+    pub(crate) fn pairs(&self) -> Vec<(&str, &str)> {
+        let mut labels = Vec::new();
+
+        if let Some(group) = &self.group {
+            labels.push(("group", group.as_str()));
+        }
+        if let Some(process_name) = &self.process_name {
+            labels.push(("process_name", process_name));
+        }
+        if let Some(cmdline) = &self.cmdline {
+            labels.push(("cmdline", cmdline));
+        }
+        if let Some(pid) = &self.pid {
+            labels.push(("pid", pid));
+        }
+        if let Some(ppid) = &self.ppid {
+            labels.push(("ppid", ppid));
+        }
+        if let Some(uid) = &self.uid {
+            labels.push(("uid", uid));
+        }
+        if let Some(user) = &self.user {
+            labels.push(("user", user));
+        }
+        if let Some(sched_policy) = &self.sched_policy {
+            labels.push(("sched_policy", sched_policy));
+        }
+        if let Some(cgroup) = &self.cgroup {
+            labels.push(("cgroup", cgroup));
+        }
+        if let Some(container_id) = &self.container_id {
+            labels.push(("container_id", container_id));
+        }
+        if let Some(pod_uid) = &self.pod_uid {
+            labels.push(("pod_uid", pod_uid));
+        }
+        if let Some(systemd_unit) = &self.systemd_unit {
+            labels.push(("systemd_unit", systemd_unit));
+        }
+        for (name, value) in &self.env {
+            labels.push((name, value));
+        }
+
+        labels
+    }
+}
+
+impl EncodeLabelSet for Labels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        encode_pairs(self.pairs(), encoder)
+    }
+}
+
+/// Labels of a host metric (e.g. a measured energy domain or a GPU), which include names reported by
+/// drivers so are escaped in the same way as process labels.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub(crate) struct HostLabels(pub(crate) Vec<(String, String)>);
+
+impl EncodeLabelSet for HostLabels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        let pairs = self
+            .0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        encode_pairs(pairs, encoder)
+    }
+}
+
+/// A label value escaped for the text formats, in which a backslash, double quote or line feed
+/// would otherwise end the value or the line (the encoder writes values as they are).
+struct Escaped<'a>(&'a str);
+
+impl EncodeLabelValue for Escaped<'_> {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), fmt::Error> {
+        for c in self.0.chars() {
+            match c {
+                '\\' => encoder.write_str("\\\\")?,
+                '"' => encoder.write_str("\\\"")?,
+                '\n' => encoder.write_str("\\n")?,
+                c => encoder.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes label names and values, escaping the values.
+pub(crate) fn encode_pairs(
+    pairs: Vec<(&str, &str)>,
+    encoder: LabelSetEncoder,
+) -> Result<(), fmt::Error> {
+    pairs
+        .into_iter()
+        .map(|(name, value)| (name, Escaped(value)))
+        .collect::<Vec<_>>()
+        .encode(encoder)
+}
+
+/// Replaces control characters (e.g. line feeds and tabs) in a label value with spaces and
+/// collapses runs of whitespace, so that odd process names and arguments stay readable.
+pub(crate) fn sanitize(value: &str) -> String {
+    value
+        .split(|c: char| c.is_control() || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Drops the pid and ppid labels of processes whose other labels (i.e. name and command line) are
+/// identical, so that they are combined into a single series.
+///
+/// Processes with a unique set of labels keep their pid and ppid labels.
+pub(crate) fn dedupe_by_cmdline(mut labels: Vec<&mut Labels>) {
+    let without_pid = |labels: &Labels| Labels {
+        pid: None,
+        ppid: None,
+        ..labels.clone()
+    };
+
+    let mut counts = HashMap::<Labels, usize>::new();
+    for labels in &labels {
+        *counts.entry(without_pid(labels)).or_default() += 1;
+    }
+
+    for labels in labels.iter_mut() {
+        let deduped = without_pid(labels);
+        if counts[&deduped] > 1 {
+            **labels = deduped;
+        }
+    }
+}
+
+/// Truncates a label value to at most `max_length` characters (excluding the trailing ellipsis
+/// that marks a truncated value).
+pub(crate) fn truncate(value: String, max_length: usize) -> String {
+    match value.char_indices().nth(max_length) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value,
+    }
+}
+
+/// A hash of a string that is stable across runs and builds (64 bit FNV-1a).
+pub(crate) fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A short hash of a label value that is stable across runs and builds.
+pub(crate) fn hash(value: &str) -> String {
+    format!("{:016x}", fnv1a(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding;
+    use kagiyama::prometheus::{
+        metrics::{counter::Counter, family::Family},
+        registry::Registry,
+    };
+    use std::hash::Hash;
+
+    fn encode<L: EncodeLabelSet + Clone + Hash + Eq + fmt::Debug + Send + Sync + 'static>(
+        labels: L,
+    ) -> String {
+        let family = Family::<L, Counter>::default();
+        family.get_or_create(&labels).inc();
+        let mut registry = Registry::default();
+        registry.register("host_energy", "Energy", family);
+        encoding::encode_text(&registry).unwrap()
+    }
+
+    #[test]
+    fn host_labels_are_escaped() {
+        let text = encode(HostLabels(vec![
+            ("domain".to_string(), r#"CPU "0" \ power"#.to_string()),
+            ("chip".to_string(), "smpro".to_string()),
+        ]));
+        assert!(
+            text.contains(r#"host_energy_total{domain="CPU \"0\" \\ power",chip="smpro"} 1"#),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let text = encode(Labels {
+            process_name: Some(r#"C:\bin\"quoted""#.to_string()),
+            cmdline: Some("line\nbreak".to_string()),
+            ..Default::default()
+        });
+        assert!(
+            text.contains(
+                r#"host_energy_total{process_name="C:\\bin\\\"quoted\"",cmdline="line\nbreak"} 1"#
+            ),
+            "{}",
+            text
+        );
+        // Every line is a comment or a complete sample
+        for line in text.lines() {
+            assert!(
+                line.starts_with('#')
+                    || (line.starts_with("host_energy_total{") && line.ends_with("} 1")),
+                "{:?} in {}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn sanitize_collapses_whitespace() {
+        assert_eq!(sanitize("a\tb\nc\r\nd"), "a b c d");
+        assert_eq!(
+            sanitize("  python3   -m\t\thttp.server  "),
+            "python3 -m http.server"
+        );
+        assert_eq!(sanitize("nul\0separated"), "nul separated");
+        assert_eq!(sanitize(" \t\n"), "");
+    }
+
+    fn process(pid: &str, cmdline: &str, user: &str) -> Labels {
+        Labels {
+            process_name: Some("worker".to_string()),
+            cmdline: Some(cmdline.to_string()),
+            pid: Some(pid.to_string()),
+            ppid: Some("1".to_string()),
+            user: Some(user.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dedupe_identical_processes() {
+        let mut processes = [
+            process("10", "worker --queue a", "app"),
+            process("11", "worker --queue a", "app"),
+            process("12", "worker --queue a", "app"),
+            process("13", "worker --queue b", "app"),
+            process("14", "worker --queue c", "app"),
+            process("15", "worker --queue c", "root"),
+        ];
+        dedupe_by_cmdline(processes.iter_mut().collect());
+
+        let deduped = Labels {
+            pid: None,
+            ppid: None,
+            ..process("10", "worker --queue a", "app")
+        };
+        assert_eq!(processes[..3], [deduped.clone(), deduped.clone(), deduped]);
+        assert_eq!(processes[3], process("13", "worker --queue b", "app"));
+        // Differ only in user
+        assert_eq!(processes[4], process("14", "worker --queue c", "app"));
+        assert_eq!(processes[5], process("15", "worker --queue c", "root"));
+    }
+}
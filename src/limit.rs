@@ -0,0 +1,86 @@
+use crate::{labels::Labels, metrics::Sample};
+use kagiyama::prometheus::{metrics::counter::Counter, registry::Registry};
+use std::collections::{HashMap, HashSet};
+
+/// Caps the number of exported series, keeping those with the most CPU time.
+///
+/// Series are ranked by their total CPU time over every collection they were present in (whether
+/// or not they were exported), which only ever increases, so the exported set only changes when a
+/// series overtakes another rather than with the CPU usage of each interval.
+///
+/// The series that are dropped are removed from the exported metrics, so when a series is
+/// exported again its counters restart from the increments of that collection (i.e. are reset).
+pub(crate) struct SeriesLimit {
+    max_series: usize,
+
+    /// Total CPU time of every current series
+    cpu_time: HashMap<Labels, f64>,
+    /// Series that were dropped in the previous collection
+    dropped: HashSet<Labels>,
+
+    dropped_series: Counter,
+}
+
+impl SeriesLimit {
+    pub(crate) fn new(max_series: usize) -> Self {
+        Self {
+            max_series,
+            cpu_time: Default::default(),
+            dropped: Default::default(),
+            dropped_series: Default::default(),
+        }
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "dropped_series",
+            "Total number of series that were not exported, or stopped being exported, because of the series limit",
+            self.dropped_series.clone(),
+        );
+    }
+
+    /// Removes the series with the least total CPU time until at most the maximum number remain.
+    pub(crate) fn apply(&mut self, totals: &mut HashMap<Labels, Sample>) {
+        let mut cpu_time = HashMap::with_capacity(totals.len());
+        for (labels, sample) in totals.iter() {
+            let total = self.cpu_time.get(labels).copied().unwrap_or(0.0) + sample.cpu_time;
+            cpu_time.insert(labels.clone(), total);
+        }
+        self.cpu_time = cpu_time;
+
+        if totals.len() <= self.max_series {
+            self.dropped.clear();
+            return;
+        }
+
+        // Ties are broken by the label values so that the order does not depend on the hash map
+        let mut ranked: Vec<(&Labels, f64)> = self
+            .cpu_time
+            .iter()
+            .map(|(labels, cpu_time)| (labels, *cpu_time))
+            .collect();
+        ranked.sort_by(|(a_labels, a_cpu_time), (b_labels, b_cpu_time)| {
+            b_cpu_time
+                .total_cmp(a_cpu_time)
+                .then_with(|| a_labels.pairs().cmp(&b_labels.pairs()))
+        });
+
+        let dropped: HashSet<Labels> = ranked[self.max_series..]
+            .iter()
+            .map(|(labels, _)| (*labels).clone())
+            .collect();
+        let newly_dropped = dropped.difference(&self.dropped).count();
+        if newly_dropped > 0 {
+            log::debug!(
+                "Dropping {} series ({} in total) over the limit of {}",
+                newly_dropped,
+                dropped.len(),
+                self.max_series
+            );
+        }
+        self.dropped_series.inc_by(newly_dropped as u64);
+
+        totals.retain(|labels, _| !dropped.contains(labels));
+        self.dropped = dropped;
+    }
+}
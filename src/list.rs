@@ -0,0 +1,89 @@
+use crate::labels::Labels;
+
+/// Processes that make up a series.
+#[derive(Default)]
+pub(crate) struct Series {
+    pub(crate) processes: usize,
+    /// Total CPU time of the processes in seconds
+    pub(crate) cpu_time: f64,
+}
+
+/// Prints a table of series, with a column for each label that is set on any series, sorted by
+/// their label values.
+pub(crate) fn print(mut series: Vec<(Labels, Series)>) {
+    series.sort_by_cached_key(|(labels, _)| {
+        labels
+            .pairs()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+    });
+
+    let mut columns: Vec<&str> = Vec::new();
+    for (labels, _) in &series {
+        for (name, _) in labels.pairs() {
+            if !columns.contains(&name) {
+                columns.push(name);
+            }
+        }
+    }
+    // Labels are always in the same order, so only a label missing from earlier series can be
+    // out of place. Labels from the environment come last, in the order they are given.
+    columns.sort_by_key(|name| {
+        Labels::NAMES
+            .iter()
+            .position(|n| n == name)
+            .unwrap_or(Labels::NAMES.len())
+    });
+
+    let mut rows: Vec<Vec<String>> = vec![columns
+        .iter()
+        .map(|name| name.to_string())
+        .chain(["processes".to_string(), "cpu_seconds".to_string()])
+        .collect()];
+    for (labels, series) in &series {
+        let pairs = labels.pairs();
+        rows.push(
+            columns
+                .iter()
+                .map(|column| {
+                    pairs
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .map(|(_, value)| value.to_string())
+                        .unwrap_or_default()
+                })
+                .chain([
+                    series.processes.to_string(),
+                    format!("{:.2}", series.cpu_time),
+                ])
+                .collect(),
+        );
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    println!(
+        "\n{} series, {} processes",
+        series.len(),
+        series
+            .iter()
+            .map(|(_, series)| series.processes)
+            .sum::<usize>()
+    );
+}
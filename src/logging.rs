@@ -0,0 +1,81 @@
+use log::{Log, Metadata, Record};
+use serde::Serialize;
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Format of log lines written to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+/// Logs with either of two env_logger loggers (configured by `RUST_LOG` in the same way), so that
+/// logging can start before the options that select the format are parsed.
+struct Logger {
+    text_logger: env_logger::Logger,
+    json_logger: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.text_logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match JSON.load(Ordering::Relaxed) {
+            true => self.json_logger.log(record),
+            false => self.text_logger.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.text_logger.flush();
+        self.json_logger.flush();
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Installs the logger, which logs text until [`set_format`] is called.
+pub(crate) fn init() {
+    let text_logger = env_logger::Builder::from_default_env().build();
+    let json_logger = env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let line = serde_json::to_string(&JsonRecord {
+                timestamp: buf.timestamp().to_string(),
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+            })
+            .map_err(std::io::Error::from)?;
+            writeln!(buf, "{}", line)
+        })
+        .build();
+
+    let max_level = text_logger.filter();
+    log::set_boxed_logger(Box::new(Logger {
+        text_logger,
+        json_logger,
+    }))
+    .expect("logger should only be initialised once");
+    log::set_max_level(max_level);
+}
+
+/// Switches the format of all further log lines.
+pub(crate) fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
@@ -1,110 +1,1385 @@
-use clap::Parser;
+mod battery;
+mod cache;
+mod config;
+mod encoding;
+mod energy;
+mod gpu;
+mod health;
+mod history;
+#[cfg(target_os = "linux")]
+mod hwmon;
+mod kubernetes;
+mod labels;
+mod limit;
+mod list;
+mod logging;
+mod metrics;
+mod network;
+mod otlp;
+mod pidfile;
+#[cfg(target_os = "macos")]
+mod powermetrics;
+mod procfs;
+mod pushgateway;
+#[cfg(target_os = "linux")]
+mod rapl;
+mod remote_write;
+mod server;
+mod signals;
+mod snapshot;
+mod state;
+mod statsd;
+mod tdp;
+mod threads;
+mod top;
+
+use crate::{
+    labels::{HostLabels, Labels},
+    logging::LogFormat,
+    metrics::{EnergyMetric, EnergyUnit, ProcessMetrics, Sample},
+    snapshot::CpuTimeSource,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use kagiyama::{
-    prometheus as prometheus_client,
     prometheus::{
-        encoding::EncodeLabelSet,
-        metrics::{counter::Counter, family::Family, info::Info},
+        metrics::{counter::Counter, family::Family, gauge::Gauge, info::Info},
         registry::Unit,
     },
-    AlwaysReady, Watcher,
+    Watcher,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, ToSocketAddrs},
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::atomic::{AtomicI64, AtomicU64},
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::{fs, sync::atomic::Ordering};
-use sysinfo::{CpuExt, Pid, ProcessExt, System, SystemExt};
-use tokio::time::{self, Duration};
+use strum_macros::EnumIter;
+use sysinfo::{
+    CpuExt, Pid, PidExt, Process, ProcessExt, ProcessRefreshKind, ProcessStatus, System, SystemExt,
+    UserExt,
+};
+use tokio::{
+    sync::mpsc,
+    time::{self, Duration, Instant, MissedTickBehavior},
+};
+
+/// Die power in Watts assumed for x86 CPUs that are not a known model.
+const DEFAULT_AVERAGE_DIE_POWER: f64 = 35.0;
+
+/// Die power in Watts assumed for Apple silicon, which is not in the TDP table.
+const APPLE_SILICON_DIE_POWER: f64 = 20.0;
+
+/// Power in Watts assumed for each core of other Arm CPUs, which range from a few cores in a
+/// single board computer to over a hundred in a server.
+const ARM_CORE_POWER: f64 = 2.5;
+
+/// Range of CPU die power in Watts outside of which a given value is probably a mistake.
+const PLAUSIBLE_DIE_POWER: RangeInclusive<f64> = 1.0..=500.0;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-    /// Address on which to serve observability endpoints.
+    /// Path to a TOML or YAML config file containing any of these options, keyed by their names
+    /// in snake case (e.g. collection_interval).
+    /// Options given on the command line or via environment variables take precedence.
+    #[clap(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+
+    /// Format of log lines, the log level is set with RUST_LOG.
+    /// JSON lines have timestamp, level, target and message fields.
+    #[clap(long, value_enum, env = "LOG_FORMAT", default_value_t)]
+    log_format: LogFormat,
+
+    /// Address on which to serve observability endpoints, as IP:PORT (IPv6 addresses in square
+    /// brackets, e.g. [::]:9090) or HOSTNAME:PORT.
     #[clap(
         short,
         long,
-        value_parser,
+        value_parser = parse_metrics_address,
         env = "METRICS_ADDRESS",
         default_value = "127.0.0.1:9090"
     )]
-    metrics_address: String,
+    metrics_address: SocketAddr,
+
+    /// Path of a unix socket on which to serve observability endpoints, instead of
+    /// --metrics-address.
+    /// A socket left behind by a previous run is removed.
+    #[clap(long, env = "METRICS_UNIX_SOCKET", conflicts_with = "metrics_address")]
+    metrics_unix_socket: Option<PathBuf>,
+
+    /// Path on which to serve metrics (e.g. /prometheus).
+    /// The /ready and /alive endpoints are not affected.
+    #[clap(
+        long,
+        value_parser = parse_metrics_path,
+        env = "METRICS_PATH",
+        default_value = "/metrics"
+    )]
+    metrics_path: String,
+
+    /// Collect metrics when /metrics is scraped rather than every collection interval, which
+    /// greatly reduces the overhead of yuugi when scrapes are infrequent.
+    /// Counters are unaffected, but CPU usage is the average since the previous scrape (and 0 for
+    /// the first scrape) and processes that start and exit between scrapes are not seen at all.
+    #[clap(long, env = "COLLECT_ON_SCRAPE", conflicts_with = "once")]
+    collect_on_scrape: bool,
+
+    /// With --collect-on-scrape, scrapes within this duration of the previous collection are
+    /// served without collecting again (e.g. when several Prometheus servers scrape at once).
+    #[clap(long, value_parser = humantime::parse_duration, env = "SCRAPE_CACHE_TTL", default_value = "1s")]
+    scrape_cache_ttl: Duration,
+
+    /// Longest time to wait before retrying when the process list cannot be read (e.g. because
+    /// /proc is briefly not accessible in a restricted container during startup).
+    /// The wait starts at the collection interval and doubles after each failure, until a
+    /// collection succeeds.
+    #[clap(long, value_parser = humantime::parse_duration, env = "MAX_REFRESH_BACKOFF", default_value = "30s")]
+    max_refresh_backoff: Duration,
+
+    /// Time since the last successful collection after which collector_up reports 0 and the
+    /// /alive endpoint fails (so that a liveness probe restarts a wedged yuugi).
+    /// Defaults to three times the collection interval (or the scrape cache TTL with
+    /// --collect-on-scrape, in which case /alive does not fail as collections only happen when
+    /// scraped).
+    #[clap(
+        long,
+        visible_alias = "max-staleness",
+        value_parser = humantime::parse_duration,
+        env = "STALENESS_THRESHOLD"
+    )]
+    staleness_threshold: Option<Duration>,
+
+    /// Collect metrics once, print them to stdout in the Prometheus text format and exit, without
+    /// serving the observability endpoints.
+    /// Processes are first collected one collection interval earlier (without printing), so that
+    /// CPU usage and power can be calculated.
+    #[clap(long, visible_alias = "dry-run", env = "ONCE")]
+    once: bool,
 
-    /// Interval in milliseconds at which to collect process information.
+    /// Path to a PEM encoded certificate (chain) with which to serve observability endpoints over
+    /// HTTPS.
+    #[clap(long, env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key for --tls-cert.
+    #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require scrapes of /metrics to present this token as "Authorization: Bearer <token>".
+    /// The health endpoints (/ready and /alive) do not require it.
+    #[clap(long, env = "METRICS_AUTH_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+
+    /// Interval at which to collect process information, as a duration (e.g. 100ms, 1s, 500us) or
+    /// a number of milliseconds.
+    /// A collection that takes longer than this is followed immediately by the next, and any
+    /// further collections that were missed are skipped rather than run back to back.
     #[clap(
         short,
         long,
-        value_parser,
+        value_parser = parse_interval,
         env = "COLLECTION_INTERVAL",
-        default_value = "100"
+        default_value = "100ms"
     )]
-    collection_interval: u64,
+    collection_interval: Duration,
+
+    /// Delay the first collection by a random time of up to this long (e.g. 50ms), so that
+    /// collections on many hosts started at the same time are spread out.
+    /// The delay is random per host but stable across restarts, as it is seeded by the hostname.
+    #[clap(long, value_parser = humantime::parse_duration, env = "COLLECTION_JITTER", default_value = "0s")]
+    collection_jitter: Duration,
 
-    /// Average power consumption of the CPU die in Watts.
+    /// Also delay every collection after the first by a random time of up to --collection-jitter,
+    /// which must then be less than the collection interval.
+    #[clap(long, env = "JITTER_EVERY_COLLECTION")]
+    jitter_every_collection: bool,
+
+    /// Average power consumption of the CPU die in Watts, optionally with a W or kW suffix.
     /// Can be assumed to be the CPUs TDP if the system is well utilised (i.e. most cores active at
     /// close to the upper frequency).
+    /// If not set, the TDP of the CPU is used if it is a known model, otherwise 35 W is assumed
+    /// (20 W for Apple silicon, and 2.5 W per core for other Arm CPUs).
+    /// Energy estimates are only meaningful if this is close to the TDP.
+    #[clap(short, long, value_parser = parse_power, env = "AVERAGE_DIE_POWER")]
+    average_die_power: Option<f64>,
+
+    /// CSV or JSON file of die powers of CPU models, used in place of the built in TDP table.
+    /// Each entry is the words identifying a model in the CPU brand string (e.g. "i7-8700K") or a
+    /// regular expression between slashes, and a power in Watts.
+    /// Takes precedence over --average-die-power, which is used if the CPU is not in the file.
+    #[clap(long, env = "POWER_DB")]
+    power_db: Option<PathBuf>,
+
+    /// File to persist the energy of each series in, which is exported as persistent_energy and
+    /// keeps increasing across restarts of yuugi and of processes.
+    /// Series are identified by their labels other than pid, ppid and cmdline. The file is
+    /// written every --state-file-interval and on shutdown, and is started from zero (with a
+    /// warning) if it is missing or cannot be read.
+    #[clap(long, env = "STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Interval at which the state file is written.
+    #[clap(long, value_parser = humantime::parse_duration, env = "STATE_FILE_INTERVAL", default_value = "1m", requires = "state_file")]
+    state_file_interval: Duration,
+
+    /// Power consumption of the CPU die in Watts (optionally with a W or kW suffix) when idle,
+    /// which is not attributed to any process.
+    /// Only the remaining (dynamic) power is apportioned between processes, for both estimated and
+    /// measured energy, and the idle energy is exported as host_idle_energy.
+    /// Must be less than the average die power. --per-core-power values are used as given.
+    #[clap(long, value_parser = parse_power, env = "IDLE_POWER")]
+    idle_power: Option<f64>,
+
+    /// On a laptop running on battery, use the power drawn from the battery (as reported by
+    /// /sys/class/power_supply) as the die power, instead of the average die power.
+    /// This is the power of the whole system, so is an overestimate of CPU power. On AC power the
+    /// average die power is used, and when CPU energy is measured (e.g. by RAPL) the battery is
+    /// only used for host_power_watts.
+    #[clap(long, env = "BATTERY_POWER")]
+    battery_power: bool,
+
+    /// Where to read the CPU time of processes from.
+    /// sysinfo integrates CPU usage over time, so only counts CPU time used after a process is
+    /// first seen, and does not provide the thread count, context switches or page faults.
+    #[clap(long, value_enum, env = "CPU_TIME_SOURCE", default_value_t)]
+    cpu_time_source: CpuTimeSource,
+
+    /// Path the proc filesystem is mounted at, for running in a container with the proc
+    /// filesystem of the host mounted elsewhere (e.g. /host/proc).
+    /// Used for everything yuugi reads from /proc itself (CPU time with --cpu-time-source proc,
+    /// cgroups and network), but the process list, names, command lines and memory are always
+    /// read from /proc by sysinfo, so the container must also share the PID namespace of the host.
+    #[clap(long, env = "HOST_PROC", default_value = "/proc")]
+    proc_path: PathBuf,
+
+    /// Unit of the per process energy metrics (energy and gpu_energy).
+    /// The unit is part of the metric name (e.g. energy_watt_hours_total), so changing it starts
+    /// new series.
+    #[clap(long, value_enum, env = "ENERGY_UNIT", default_value_t)]
+    energy_unit: EnergyUnit,
+
+    /// Whether to export the cumulative energy of each process, its power (in Watts, averaged over
+    /// the interval since the previous collection) or both.
+    #[clap(long, value_enum, env = "ENERGY_METRIC", default_value_t)]
+    energy_metric: EnergyMetric,
+
+    /// Number of clock ticks (jiffies) per second used for CPU times in /proc, rather than asking
+    /// the system (via sysconf).
+    /// This is almost always 100 on Linux, which is assumed if sysconf fails (e.g. with some
+    /// libcs other than glibc). Only used with --cpu-time-source proc.
+    /// A warning is logged if the rate at which the kernel counts CPU time over the first 10
+    /// seconds differs from this.
     #[clap(
-        short,
         long,
-        value_parser,
-        env = "AVERAGE_DIE_POWER",
-        default_value = "35"
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "CLOCK_TICKS_PER_SECOND"
     )]
-    average_die_power: f64,
+    clock_ticks_per_second: Option<u64>,
+
+    /// Value of the hostname label on all metrics, instead of the system hostname (which is often
+    /// meaningless in a container).
+    #[clap(long, env = "INSTANCE_NAME")]
+    hostname_override: Option<String>,
+
+    /// Static label to add to all metrics, as KEY=VALUE (e.g. environment=prod), can be given
+    /// multiple times or comma separated.
+    /// Labels are added alongside hostname (and sent as resource attributes to OTLP and as tags
+    /// to StatsD), so do not add any series. Names of labels that yuugi sets itself are rejected.
+    /// In a config file labels can be given as a section, with the names as keys.
+    #[clap(long, value_parser = parse_label, env = "EXTRA_LABELS", value_delimiter = ',')]
+    label: Vec<(String, String)>,
+
+    /// Environment variable of each process to add as a label (named after the variable in lower
+    /// case, e.g. APP becomes app), can be given multiple times or comma separated (Linux only).
+    /// The label is omitted for processes without the variable, and for those whose environment
+    /// cannot be read (only the owner of a process and root can usually read it).
+    /// The environment is read when a process is first seen, so its labels stay the same even if
+    /// its environment later becomes readable or unreadable (e.g. after it changes user).
+    /// Every distinct value is a separate series, so a variable that is unique per process (e.g.
+    /// a request ID) creates a series per process even when aggregating.
+    #[clap(long, value_parser = parse_env_label, env = "LABEL_FROM_ENV", value_delimiter = ',')]
+    label_from_env: Vec<(String, String)>,
+
+    /// Power consumption in Watts of a logical CPU, as CPU=WATTS (e.g. 0=8.5), can be given
+    /// multiple times or comma separated.
+    /// When energy is estimated from CPU time, the CPU time of a process is attributed the power of
+    /// the CPU it last ran on, which improves accuracy on CPUs with cores of different types.
+    /// CPUs that are not listed use the average core power.
+    #[clap(long, value_parser = parse_core_power, env = "PER_CORE_POWER", value_delimiter = ',')]
+    per_core_power: Vec<(usize, f64)>,
+
+    /// Only export processes whose name matches this regular expression.
+    /// All processes are exported if not set.
+    #[clap(long, value_parser = Regex::new, env = "PROCESS_FILTER")]
+    process_filter: Option<Regex>,
+
+    /// Do not export processes whose cgroup path matches this regular expression (e.g.
+    /// ^/kubepods to skip all Kubernetes pods).
+    /// Exclusion takes precedence, so a process in a matching cgroup is not exported even if it
+    /// matches --process-filter or is in a --pidfile.
+    /// The path is that of the cgroup with the CPU controller (see --group-by-cgroup), processes
+    /// whose cgroup cannot be read (e.g. on platforms other than Linux) are not excluded. As for
+    /// labels, the cgroup is read when a process is first seen.
+    #[clap(long, value_parser = Regex::new, env = "EXCLUDE_CGROUP")]
+    exclude_cgroup: Option<Regex>,
+
+    /// Only export the processes whose PIDs are in these PID files, and all of their descendants.
+    /// The files are read every collection so that restarted services are followed, and a file
+    /// is ignored (with a warning) while its PID is not running or has been reused by a process
+    /// that started after the file was written.
+    #[clap(long, env = "PIDFILE", value_delimiter = ',')]
+    pidfile: Vec<PathBuf>,
+
+    /// Only export processes that have used at least this much CPU time in total (in seconds).
+    /// This trades completeness for a smaller registry, as most processes on a typical host are
+    /// idle.
+    /// As energy is apportioned between exported processes, any energy used by the excluded
+    /// processes is attributed to the others when it is measured.
+    #[clap(long, env = "MIN_CPU_SECONDS", default_value = "0")]
+    min_cpu_seconds: f64,
+
+    /// Only export processes once they have been seen in this many consecutive collections.
+    /// This keeps short lived processes (e.g. compiler invocations on a build server) from each
+    /// creating series. All of the CPU time of a process is counted once it is exported, but as
+    /// with --min-cpu-seconds the energy used by processes that are not yet exported is attributed
+    /// to the others when it is measured.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "MIN_OBSERVATIONS",
+        default_value = "1"
+    )]
+    min_observations: u32,
+
+    /// Maximum number of per process series to export, those with the least total CPU time are
+    /// dropped once there are more.
+    /// A safety valve against hosts that occasionally start thousands of processes, the number of
+    /// series that are dropped is exported as dropped_series_total. Energy apportioned to dropped
+    /// series is not exported.
+    /// A dropped series that is exported again starts from zero, which Prometheus treats as a
+    /// counter reset.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "MAX_SERIES"
+    )]
+    max_series: Option<u64>,
+
+    /// Do not export the yuugi process itself.
+    #[clap(long, env = "EXCLUDE_SELF")]
+    exclude_self: bool,
+
+    /// Do not export kernel threads.
+    /// A process is considered to be a kernel thread if it has no command line or its name is
+    /// wrapped in square brackets (e.g. [kworker/0:1]), so this also excludes zombie processes.
+    #[clap(long, env = "EXCLUDE_KERNEL_THREADS")]
+    exclude_kernel_threads: bool,
+
+    /// Aggregate metrics across all processes with the same name, rather than exporting each PID
+    /// separately.
+    /// This drops the pid and cmdline labels, greatly reducing the number of series at the cost of
+    /// no longer being able to distinguish between individual processes.
+    #[clap(long, env = "AGGREGATE_BY_NAME")]
+    aggregate_by_name: bool,
+
+    /// Aggregate metrics across all processes in the same cgroup, rather than exporting each PID
+    /// separately.
+    /// Series are labelled with the cgroup path, and the container ID and Kubernetes pod UID
+    /// where these can be recognised from the path.
+    /// The cgroup of a process is read when it is first seen, so a process that is later moved to
+    /// another cgroup stays in the series of the first.
+    #[clap(long, env = "GROUP_BY_CGROUP", conflicts_with = "aggregate_by_name")]
+    group_by_cgroup: bool,
+
+    /// Aggregate metrics across all processes managed by the same systemd unit (e.g.
+    /// nginx.service), rather than exporting each PID separately.
+    /// The unit is the innermost service or scope (or otherwise slice) in the cgroup path of a
+    /// process, processes not managed by systemd are aggregated into a single series with an
+    /// empty systemd_unit label.
+    #[clap(
+        long,
+        env = "GROUP_BY_UNIT",
+        conflicts_with_all = ["aggregate_by_name", "group_by_cgroup"]
+    )]
+    group_by_unit: bool,
+
+    /// Combine processes whose labels are identical apart from the pid (i.e. with the same name
+    /// and command line, such as a pool of worker processes) into a single series, without the
+    /// pid and ppid labels.
+    /// Processes with a unique command line are still exported separately, so a process moves to
+    /// a different series when the number of identical processes changes to or from one.
+    #[clap(
+        long,
+        env = "DEDUPE_BY_CMDLINE",
+        conflicts_with_all = ["aggregate_by_name", "group_by_cgroup", "group_by_unit", "no_cmdline"]
+    )]
+    dedupe_by_cmdline: bool,
+
+    /// Aggregate metrics across all processes whose command line matches a regular expression,
+    /// as NAME=REGEX (e.g. app=--config=/etc/app), can be given multiple times.
+    /// Series are labelled with the name of the first group that matches, processes that match
+    /// no group are aggregated into a group named "other" (unless --drop-ungrouped is set).
+    /// In a config file groups can be given as a section, with the names as keys.
+    #[clap(
+        long,
+        value_parser = parse_cmdline_group,
+        env = "CMDLINE_GROUP",
+        conflicts_with_all = ["aggregate_by_name", "group_by_cgroup", "group_by_unit", "dedupe_by_cmdline"]
+    )]
+    cmdline_group: Vec<(String, Regex)>,
+
+    /// Do not export processes that match no --cmdline-group, rather than aggregating them into
+    /// the "other" group.
+    #[clap(long, env = "DROP_UNGROUPED", requires = "cmdline_group")]
+    drop_ungrouped: bool,
+
+    /// Maximum length (in characters) of the cmdline label, longer command lines are truncated
+    /// and marked with an ellipsis.
+    /// Truncation happens before labels are constructed, so this also bounds the size of the
+    /// series keys held in memory.
+    #[clap(long, env = "MAX_CMDLINE_LENGTH", default_value = "256")]
+    max_cmdline_length: usize,
+
+    /// Replace the cmdline label with a short stable hash of the full command line.
+    #[clap(long, env = "HASH_CMDLINE")]
+    hash_cmdline: bool,
+
+    /// Do not export the cmdline label at all, for when command lines may contain secrets (e.g.
+    /// passwords given as arguments).
+    /// Processes with the same name are still separate series by their pid label, command lines
+    /// are only used to match --cmdline-group patterns.
+    #[clap(long, env = "NO_CMDLINE", conflicts_with = "hash_cmdline")]
+    no_cmdline: bool,
+
+    /// Do not resolve the user ID of each process to a username, omitting the user label.
+    /// The uid label is still exported.
+    #[clap(long, env = "NO_RESOLVE_USERNAMES")]
+    no_resolve_usernames: bool,
+
+    /// Label per PID series with the scheduling policy of the process (e.g. other, batch, fifo).
+    /// Only available on Linux, a process that changes policy starts a new series.
+    #[clap(long, env = "SCHED_POLICY_LABEL")]
+    sched_policy_label: bool,
+
+    /// Label per PID series with the systemd unit managing the process (e.g. nginx.service), from
+    /// its cgroup (Linux only), see --group-by-unit.
+    /// The cgroup is only read when a process is first seen, as with --group-by-cgroup.
+    #[clap(long, env = "SYSTEMD_UNIT_LABEL")]
+    systemd_unit_label: bool,
+
+    /// Export power, energy and utilisation of NVIDIA GPUs via NVML, and attribute GPU energy to
+    /// processes by their share of GPU utilisation (where the driver reports it).
+    /// Does nothing if there is no NVIDIA driver.
+    #[clap(long, env = "ENABLE_GPU")]
+    enable_gpu: bool,
+
+    /// Export the bytes received and transmitted by the network namespace of each series
+    /// (net_rx_bytes and net_tx_bytes, labelled by netns).
+    /// Linux only counts traffic per network namespace rather than per process, so all processes
+    /// sharing a namespace (e.g. all processes not in a container) report the same values.
+    #[clap(long, env = "ENABLE_NETWORK")]
+    enable_network: bool,
+
+    /// Export the CPU time of every thread of each exported process (thread_cpu_time, labelled by
+    /// tid and thread_name), for finding a busy thread.
+    /// This creates a series per thread, so is best combined with --process-filter. Requires
+    /// --cpu-time-source proc.
+    #[clap(long, env = "PER_THREAD")]
+    per_thread: bool,
+
+    /// Export a histogram of the CPU usage of each series in every collection
+    /// (cpu_usage_ratio_distribution), to identify bursty workloads.
+    /// Each series of the histogram holds a count for each of its 8 buckets plus a sum and count,
+    /// so this roughly multiplies the memory used per series by ten.
+    #[clap(long, env = "ENABLE_HISTOGRAMS")]
+    enable_histograms: bool,
+
+    /// URL of an OTLP/HTTP collector metrics endpoint (e.g. http://localhost:4318/v1/metrics) to
+    /// push per process metrics to every collection interval.
+    /// The Prometheus endpoint remains available when this is set.
+    #[clap(long, env = "OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Address (host:port) of a StatsD server to send per process metrics to over UDP every
+    /// collection interval, with labels as DogStatsD tags.
+    /// The Prometheus endpoint remains available when this is set.
+    #[clap(long, env = "STATSD_ADDRESS")]
+    statsd_address: Option<String>,
+
+    /// URL of a Prometheus Pushgateway (e.g. http://localhost:9091, or an https URL) to push all
+    /// metrics to when shutting down, for use with short lived batch jobs that may exit before
+    /// being scraped.
+    #[clap(long, env = "PUSHGATEWAY_URL")]
+    pushgateway_url: Option<String>,
+
+    /// Value of the job grouping label when pushing to a Pushgateway.
+    #[clap(long, env = "PUSHGATEWAY_JOB", default_value = "yuugi")]
+    pushgateway_job: String,
+
+    /// Value of the instance grouping label when pushing to a Pushgateway.
+    /// Defaults to the hostname.
+    #[clap(long, env = "PUSHGATEWAY_INSTANCE")]
+    pushgateway_instance: Option<String>,
+
+    /// Also push to the Pushgateway after every collection, rather than only when shutting down.
+    /// These pushes are sent in the background, and skipped while an earlier push is still being
+    /// sent, so that a slow Pushgateway does not delay collection.
+    #[clap(
+        long,
+        env = "PUSHGATEWAY_EVERY_COLLECTION",
+        requires = "pushgateway_url"
+    )]
+    pushgateway_every_collection: bool,
+
+    /// URL of a Prometheus remote write endpoint (e.g. http://localhost:9090/api/v1/write, or an
+    /// https URL, whose certificate is verified against the system's trusted roots) to write all
+    /// metrics to, for hosts without a Prometheus server to scrape them.
+    /// Writes that fail are retried a few times, after which their samples are dropped (counted
+    /// in remote_write_dropped_samples_total) rather than buffered.
+    #[clap(long, env = "REMOTE_WRITE_URL")]
+    remote_write_url: Option<String>,
+
+    /// Interval at which to write metrics to the remote write endpoint, the values of the most
+    /// recent collection are written once this has elapsed since the previous write.
+    #[clap(
+        long,
+        value_parser = humantime::parse_duration,
+        env = "REMOTE_WRITE_INTERVAL",
+        default_value = "15s",
+        requires = "remote_write_url"
+    )]
+    remote_write_interval: Duration,
+
+    /// Username for HTTP basic authentication to the remote write endpoint.
+    #[clap(long, env = "REMOTE_WRITE_USERNAME", requires = "remote_write_url")]
+    remote_write_username: Option<String>,
+
+    /// Password for HTTP basic authentication to the remote write endpoint.
+    #[clap(
+        long,
+        env = "REMOTE_WRITE_PASSWORD",
+        hide_env_values = true,
+        requires = "remote_write_username"
+    )]
+    remote_write_password: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the labels that would be exported for each series, with the number of processes
+    /// and total CPU time in each, then exit.
+    /// All filtering and grouping options apply, so this can be used to check filters and
+    /// estimate the number of series. The CPU time is the total since each process started
+    /// (always 0 with --cpu-time-source sysinfo, as only a single collection is made).
+    List,
+
+    /// Show a live table of series in the terminal, updated every collection, instead of serving
+    /// metrics.
+    /// Press s to change the column the table is sorted by and q to quit. Logs are written to
+    /// stderr, so should be redirected when RUST_LOG is set.
+    Top,
+}
+
+impl Cli {
+    /// Parses command line arguments and environment variables, then applies any options from a
+    /// config file that were not already set.
+    fn parse_with_config() -> Result<Self> {
+        let command = Self::command();
+        let matches = command.clone().get_matches();
+        let cli = Self::from_arg_matches(&matches)?;
+
+        match &cli.config {
+            Some(path) => {
+                let config = config::load(path)?;
+                let mut args: Vec<String> = std::env::args().collect();
+                args.splice(1..1, config::to_args(&command, &matches, config)?);
+                Ok(Self::parse_from(args))
+            }
+            None => Ok(cli),
+        }
+    }
+}
+
+fn parse_core_power(value: &str) -> Result<(usize, f64)> {
+    let (cpu, power) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected CPU=WATTS"))?;
+    Ok((
+        cpu.trim().parse().context("invalid CPU index")?,
+        power.trim().parse().context("invalid power")?,
+    ))
+}
+
+/// Names of labels that yuugi sets on some metric, other than the process labels and the
+/// Kubernetes pod labels.
+const RESERVED_LABELS: &[&str] = &[
+    "hostname",
+    // Host metrics
+    "battery",
+    "chip",
+    "domain",
+    "gpu",
+    "netns",
+    "socket",
+    "state",
+    "uuid",
+    "zone",
+    // Thread metrics
+    "thread_name",
+    "tid",
+    // Histograms and summaries
+    "le",
+    "quantile",
+    // Info metrics
+    "arch",
+    "average_core_power",
+    "average_die_power",
+    "average_die_power_source",
+    "build_timestamp",
+    "clock_ticks_source",
+    "collection_interval",
+    "cpu_time_source",
+    "energy_source",
+    "git_commit",
+    "idle_power",
+    "jiffy_in_seconds",
+    "kernel_version",
+    "model",
+    "num_physical_cores",
+    "os",
+    "os_version",
+    "power_db_entry",
+    "rustc_version",
+    "vendor",
+    "version",
+];
+
+/// Checks that a label name is valid and not used by yuugi.
+fn check_label_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!(
+            "invalid label name \"{}\" (must match [a-zA-Z_][a-zA-Z0-9_]*)",
+            name
+        );
+    }
+    if name.starts_with("__") {
+        bail!("label names starting with __ are reserved");
+    }
+    if RESERVED_LABELS.contains(&name)
+        || Labels::NAMES.contains(&name)
+        || kubernetes::LABEL_NAMES.contains(&name)
+    {
+        bail!("label {} is set by yuugi", name);
+    }
+    Ok(())
+}
+
+fn parse_label(value: &str) -> Result<(String, String)> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=VALUE"))?;
+
+    check_label_name(name)?;
+    if value.is_empty() {
+        bail!("label value must not be empty");
+    }
+    // Registry labels are written as they are, unlike the escaped process labels
+    if value
+        .chars()
+        .any(|c| c == '"' || c == '\\' || c.is_control())
+    {
+        bail!("label value must not contain quotes, backslashes or control characters");
+    }
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parses an environment variable name, returning it and the name of its label.
+fn parse_env_label(value: &str) -> Result<(String, String)> {
+    if value.is_empty() || value.contains(['=', '\0']) {
+        bail!("invalid environment variable name \"{}\"", value);
+    }
+    let name = value.to_lowercase();
+    check_label_name(&name)?;
+    Ok((value.to_string(), name))
+}
+
+fn parse_metrics_path(value: &str) -> Result<String> {
+    if !value.starts_with('/') {
+        bail!("must start with /");
+    }
+    if matches!(value, "/ready" | "/alive") {
+        bail!("{} is used by a health endpoint", value);
+    }
+    Ok(value.to_string())
+}
+
+fn parse_metrics_address(value: &str) -> Result<SocketAddr> {
+    if let Ok(address) = value.parse() {
+        return Ok(address);
+    }
+
+    // An IPv6 address without brackets cannot be told apart from one with a port
+    if value.matches(':').count() > 1 && !value.starts_with('[') {
+        bail!("IPv6 addresses must be in square brackets, e.g. [::1]:9090");
+    }
+
+    value
+        .to_socket_addrs()
+        .context("expected IP:PORT or HOSTNAME:PORT")?
+        .next()
+        .ok_or_else(|| anyhow!("did not resolve to an address"))
+}
+
+/// Parses a power in Watts, which may have a W or kW suffix (e.g. 15, 15W or 0.5kW).
+fn parse_power(value: &str) -> Result<f64> {
+    let value = value.trim();
+    let (value, scale) = if let Some(value) = value.strip_suffix("kW") {
+        (value, 1000.0)
+    } else {
+        (value.strip_suffix('W').unwrap_or(value), 1.0)
+    };
+    let power = value.trim_end().parse::<f64>().context("invalid power")? * scale;
+    if !power.is_finite() || power <= 0.0 {
+        bail!("must be greater than 0 W");
+    }
+    Ok(power)
+}
+
+fn parse_cmdline_group(value: &str) -> Result<(String, Regex)> {
+    let (name, pattern) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected NAME=REGEX"))?;
+    if name.is_empty() {
+        bail!("group name must not be empty");
+    }
+    let pattern = Regex::new(pattern).context("invalid regular expression")?;
+    Ok((name.to_string(), pattern))
 }
 
-fn get_process_jiffies(pid: &Pid) -> u64 {
-    match fs::read_to_string(format!("/proc/{pid}/stat")) {
-        Ok(contents) => {
-            let contents: Vec<&str> = contents.split(' ').collect();
-            let utime: u64 = contents[13].parse().unwrap();
-            let stime: u64 = contents[14].parse().unwrap();
-            log::trace!("PID {}: user={} kernel={}", pid, utime, stime);
-            utime + stime
+fn parse_interval(value: &str) -> Result<Duration> {
+    // Bare numbers are milliseconds, as this option used to only accept milliseconds
+    let interval = match value.trim().parse::<u64>() {
+        Ok(ms) => Duration::from_millis(ms),
+        Err(_) => humantime::parse_duration(value).context("invalid duration")?,
+    };
+
+    if interval < Duration::from_millis(1) {
+        bail!("must be at least 1ms");
+    }
+    Ok(interval)
+}
+
+#[derive(Clone, Serialize, EnumIter, PartialEq, Hash, Eq)]
+enum ReadinessConditions {
+    FirstCollectionComplete,
+}
+
+/// Die power to assume for a CPU that is not a known model, and what it is assumed for.
+fn default_die_power(num_physical_cores: usize) -> (f64, &'static str) {
+    match (std::env::consts::ARCH, std::env::consts::OS) {
+        ("aarch64", "macos") => (APPLE_SILICON_DIE_POWER, "Apple silicon"),
+        ("aarch64" | "arm", _) => (
+            (ARM_CORE_POWER * num_physical_cores as f64)
+                .clamp(*PLAUSIBLE_DIE_POWER.start(), *PLAUSIBLE_DIE_POWER.end()),
+            "Arm",
+        ),
+        _ => (DEFAULT_AVERAGE_DIE_POWER, "x86"),
+    }
+}
+
+/// Number of physical cores that die power is divided between.
+///
+/// This can be reported as 0 in some restricted or emulated environments, in which case the
+/// number of logical CPUs (or 1) is used, so that core power is never infinite.
+fn physical_cores() -> usize {
+    let physical = num_cpus::get_physical();
+    let cores = physical_core_count(physical, num_cpus::get());
+    if physical == 0 {
+        log::warn!(
+            "Number of physical cores is reported as 0, using the number of logical CPUs ({}) instead",
+            cores
+        );
+    }
+    cores
+}
+
+/// Number of physical cores given the reported physical and logical CPU counts.
+fn physical_core_count(physical: usize, logical: usize) -> usize {
+    match physical {
+        0 => logical.max(1),
+        cores => cores,
+    }
+}
+
+/// Kernel threads have no command line, and are shown with their name in square brackets by
+/// tools that fall back to the name when there is no command line.
+///
+/// Zombie processes also have no command line, but are not kernel threads.
+fn is_kernel_thread(process: &Process) -> bool {
+    process.status() != ProcessStatus::Zombie
+        && (process.cmd().is_empty()
+            || (process.name().starts_with('[') && process.name().ends_with(']')))
+}
+
+/// Checks if a process is excluded by any of the process filtering options.
+///
+/// `pidfile_processes` are the processes from any PID files, all others are excluded.
+fn is_excluded(
+    args: &Cli,
+    self_pid: Pid,
+    pidfile_processes: Option<&HashSet<Pid>>,
+    pid: &Pid,
+    process: &Process,
+    details: &ProcessDetails,
+) -> bool {
+    if args.exclude_self && *pid == self_pid {
+        return true;
+    }
+    if pidfile_processes.is_some_and(|processes| !processes.contains(pid)) {
+        return true;
+    }
+    if args.exclude_kernel_threads && is_kernel_thread(process) {
+        return true;
+    }
+    if let Some(pattern) = &args.exclude_cgroup {
+        if details
+            .cgroup
+            .as_ref()
+            .is_some_and(|cgroup| pattern.is_match(&cgroup.path))
+        {
+            return true;
         }
+    }
+    match &args.process_filter {
+        Some(filter) => !filter.is_match(process.name()),
+        None => false,
+    }
+}
+
+/// Labels from the --label-from-env environment variables of a process, none if its environment
+/// cannot be read (it is usually only readable by the owner of the process and root).
+fn environment_labels(args: &Cli, pid: &Pid) -> Vec<(String, String)> {
+    // /proc only exists on Linux
+    if args.label_from_env.is_empty() || !cfg!(target_os = "linux") {
+        return Vec::new();
+    }
+
+    let variables = match procfs::get_environment(&args.proc_path, pid) {
+        Ok(variables) => variables,
         Err(e) => {
-            log::warn!("Failed to get process time PID={}, err: {}", pid, e);
-            0
+            log::trace!("Failed to read environment of PID {}, err: {}", pid, e);
+            return Vec::new();
         }
+    };
+    args.label_from_env
+        .iter()
+        .filter_map(|(variable, name)| {
+            let value = labels::sanitize(variables.get(variable)?);
+            (!value.is_empty()).then(|| (name.clone(), value))
+        })
+        .collect()
+}
+
+/// Details of a process that are read from /proc when it is first seen, rather than every
+/// collection.
+struct ProcessDetails {
+    /// Only read if an option uses it, `None` if it cannot be read
+    cgroup: Option<procfs::Cgroup>,
+    /// Labels from the environment (--label-from-env), which is the one the process was started
+    /// with so does not change
+    env: Vec<(String, String)>,
+}
+
+fn read_process_details(args: &Cli, pid: &Pid) -> ProcessDetails {
+    // cgroups only exist on Linux
+    let cgroup_used = cfg!(target_os = "linux")
+        && (args.group_by_cgroup
+            || args.group_by_unit
+            || args.systemd_unit_label
+            || args.exclude_cgroup.is_some());
+
+    ProcessDetails {
+        cgroup: match cgroup_used {
+            true => procfs::get_cgroup(&args.proc_path, pid),
+            false => None,
+        },
+        env: environment_labels(args, pid),
+    }
+}
+
+/// Labels for a process, or `None` if the process exited before they could be determined or
+/// should not be exported.
+fn process_labels(
+    args: &Cli,
+    sys: &System,
+    pid: &Pid,
+    process: &Process,
+    details: &ProcessDetails,
+) -> Option<Labels> {
+    let env = details.env.clone();
+
+    if !args.cmdline_group.is_empty() {
+        let cmdline = process.cmd().join(" ");
+        let group = match args
+            .cmdline_group
+            .iter()
+            .find(|(_, pattern)| pattern.is_match(&cmdline))
+        {
+            Some((name, _)) => name.clone(),
+            None if args.drop_ungrouped => return None,
+            None => "other".to_string(),
+        };
+        return Some(Labels {
+            group: Some(group),
+            process_name: None,
+            cmdline: None,
+            pid: None,
+            ppid: None,
+            uid: None,
+            user: None,
+            sched_policy: None,
+            cgroup: None,
+            container_id: None,
+            pod_uid: None,
+            systemd_unit: None,
+            env,
+        });
+    }
+
+    if args.aggregate_by_name {
+        return Some(Labels {
+            group: None,
+            process_name: Some(labels::sanitize(process.name())),
+            cmdline: None,
+            pid: None,
+            ppid: None,
+            uid: None,
+            user: None,
+            sched_policy: None,
+            cgroup: None,
+            container_id: None,
+            pod_uid: None,
+            systemd_unit: None,
+            env,
+        });
+    }
+
+    if args.group_by_unit {
+        let cgroup = details.cgroup.as_ref()?;
+        return Some(Labels {
+            group: None,
+            process_name: None,
+            cmdline: None,
+            pid: None,
+            ppid: None,
+            uid: None,
+            user: None,
+            sched_policy: None,
+            cgroup: None,
+            container_id: None,
+            pod_uid: None,
+            // An empty label for processes not managed by systemd, so that their series still has
+            // a label set
+            systemd_unit: Some(cgroup.systemd_unit.clone().unwrap_or_default()),
+            env,
+        });
     }
+
+    if args.group_by_cgroup {
+        let cgroup = details.cgroup.as_ref()?;
+        return Some(Labels {
+            group: None,
+            process_name: None,
+            cmdline: None,
+            pid: None,
+            ppid: None,
+            uid: None,
+            user: None,
+            sched_policy: None,
+            cgroup: Some(cgroup.path.clone()),
+            container_id: cgroup.container_id.clone(),
+            pod_uid: cgroup.pod_uid.clone(),
+            systemd_unit: cgroup.systemd_unit.clone(),
+            env,
+        });
+    }
+
+    let cmdline = process.cmd().join(" ");
+    let cmdline = match (args.no_cmdline, args.hash_cmdline) {
+        (true, _) => None,
+        (false, true) => Some(labels::hash(&cmdline)),
+        (false, false) => Some(labels::truncate(
+            labels::sanitize(&cmdline),
+            args.max_cmdline_length,
+        )),
+    };
+
+    let uid = process.user_id();
+    let user = match args.no_resolve_usernames {
+        true => None,
+        false => uid.map(|uid| match sys.get_user_by_id(uid) {
+            Some(user) => user.name().to_string(),
+            None => uid.to_string(),
+        }),
+    };
+
+    let systemd_unit = match args.systemd_unit_label {
+        true => details
+            .cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.systemd_unit.clone()),
+        false => None,
+    };
+
+    Some(Labels {
+        group: None,
+        process_name: Some(labels::sanitize(process.name())),
+        cmdline,
+        pid: Some(pid.to_string()),
+        ppid: process.parent().map(|ppid| ppid.to_string()),
+        uid: uid.map(|uid| uid.to_string()),
+        user,
+        sched_policy: None,
+        cgroup: None,
+        container_id: None,
+        pod_uid: None,
+        systemd_unit,
+        env,
+    })
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
-struct Labels {
-    process_name: String,
-    cmdline: String,
-    pid: String,
+/// Collects every process once and prints the series they would be exported as.
+fn list_series(args: &Cli) -> Result<()> {
+    let mut snapshot_source = args.cpu_time_source.snapshot_source(
+        &args.proc_path,
+        args.clock_ticks_per_second,
+        false,
+    )?;
+    let self_pid = Pid::from_u32(std::process::id());
+
+    let mut sys = System::new_all();
+    sys.refresh_processes_specifics(ProcessRefreshKind::new().with_cpu().with_user());
+
+    let pidfile_processes = (!args.pidfile.is_empty())
+        .then(|| pidfile::PidFiles::new(args.pidfile.clone()).processes(&sys));
+
+    let mut processes = Vec::new();
+    for (pid, process) in sys.processes() {
+        let details = read_process_details(args, pid);
+        if is_excluded(
+            args,
+            self_pid,
+            pidfile_processes.as_ref(),
+            pid,
+            process,
+            &details,
+        ) {
+            continue;
+        }
+
+        let mut labels = match process_labels(args, &sys, pid, process, &details) {
+            Some(labels) => labels,
+            None => continue,
+        };
+
+        if let Some(snapshot) = snapshot_source.collect(pid, process) {
+            if snapshot.cpu_time < args.min_cpu_seconds {
+                continue;
+            }
+            if args.sched_policy_label && labels.pid.is_some() {
+                labels.sched_policy = snapshot.sched_policy.clone();
+            }
+            processes.push((labels, snapshot.cpu_time));
+        }
+    }
+    snapshot_source.end_collection();
+    if args.dedupe_by_cmdline {
+        labels::dedupe_by_cmdline(processes.iter_mut().map(|(labels, _)| labels).collect());
+    }
+
+    let mut series = HashMap::<Labels, list::Series>::new();
+    for (labels, cpu_time) in processes {
+        let series = series.entry(labels).or_default();
+        series.processes += 1;
+        series.cpu_time += cpu_time;
+    }
+
+    list::print(series.into_iter().collect());
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() {
-    env_logger::init();
+async fn main() -> Result<()> {
+    logging::init();
+    server::install_crypto_provider();
 
-    let args = Cli::parse();
+    let args = Cli::parse_with_config()?;
+    logging::set_format(args.log_format);
 
-    let mut watcher = Watcher::<AlwaysReady>::default();
-    watcher
-        .start_server(args.metrics_address.parse().unwrap())
-        .await;
+    if let Some(Command::List) = args.command {
+        return list_series(&args);
+    }
+    let show_top = matches!(args.command, Some(Command::Top));
+    if show_top && (args.once || args.collect_on_scrape) {
+        bail!("top cannot be used with --once or --collect-on-scrape");
+    }
+    let mut label_names = HashSet::new();
+    for (name, _) in &args.label {
+        if !label_names.insert(name) {
+            bail!("--label {} is given more than once", name);
+        }
+    }
+    for (variable, name) in &args.label_from_env {
+        if !label_names.insert(name) {
+            bail!(
+                "--label-from-env {} is given more than once or is also given to --label",
+                variable
+            );
+        }
+    }
+    if !args.label_from_env.is_empty() {
+        log::warn!(
+            "Adding labels from the environment variables {}, every distinct value of which is a separate series",
+            args.label_from_env
+                .iter()
+                .map(|(variable, _)| variable.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let watcher = Watcher::<ReadinessConditions>::default();
+    let mut readiness = server::Readiness::new(&watcher);
+    let metrics_address = match &args.metrics_unix_socket {
+        Some(path) => server::Address::Unix(path.clone()),
+        None => server::Address::Tcp(args.metrics_address),
+    };
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(server::load_tls(cert, key)?),
+        _ => None,
+    };
+    let (collection_request_tx, mut collection_requests) = mpsc::channel(16);
+    let collector_up = health::CollectorUp::new(args.staleness_threshold.unwrap_or(
+        match args.collect_on_scrape {
+            true => args.scrape_cache_ttl * 3,
+            false => args.collection_interval * 3,
+        },
+    ));
+    let server = match args.once || show_top {
+        true => None,
+        false => Some(
+            server::Server::start(
+                metrics_address,
+                args.metrics_path.clone(),
+                tls,
+                args.auth_token.clone(),
+                watcher.clone(),
+                match args.collect_on_scrape {
+                    true => readiness.clone(),
+                    false => readiness.clone().with_liveness(collector_up.clone()),
+                },
+                args.collect_on_scrape.then_some(collection_request_tx),
+            )
+            .await?,
+        ),
+    };
 
     let mut sys = System::new_all();
     sys.refresh_all();
 
-    // TODO: Discover this value from CPU model (for TDP) or via a suitable API for CPUs that
-    // support measuring actual values
-    let average_die_power = args.average_die_power;
+    let num_physical_cores = physical_cores();
+
+    let power_db = args
+        .power_db
+        .as_deref()
+        .map(tdp::PowerDb::load)
+        .transpose()?;
+    let power_db_entry = power_db
+        .as_ref()
+        .and_then(|db| db.lookup(sys.global_cpu_info().brand()));
+    if power_db.is_some() && power_db_entry.is_none() {
+        log::warn!(
+            "CPU model \"{}\" is not in the power database",
+            sys.global_cpu_info().brand()
+        );
+    }
+
+    let (average_die_power, average_die_power_source) = match (
+        power_db_entry,
+        args.average_die_power,
+    ) {
+        (Some((entry, power)), _) => {
+            log::info!("CPU model matches \"{}\" in the power database", entry);
+            (power, "power_db")
+        }
+        (None, Some(power)) => (power, "user"),
+        (None, None) => match tdp::lookup(sys.global_cpu_info().brand()) {
+            Some(power) => (power, "discovered"),
+            None => {
+                let (power, assumed_for) = default_die_power(num_physical_cores);
+                log::warn!(
+                    "CPU model \"{}\" has no known TDP, assuming {} W ({} default), energy estimates are unlikely to be accurate (set --average-die-power)",
+                    sys.global_cpu_info().brand(),
+                    power,
+                    assumed_for
+                );
+                (power, "default")
+            }
+        },
+    };
+    // Discovered and default powers are known to be plausible
+    if matches!(average_die_power_source, "user" | "power_db")
+        && !PLAUSIBLE_DIE_POWER.contains(&average_die_power)
+    {
+        log::warn!(
+            "Average die power of {} W is outside the plausible range of {} to {} W, energy estimates are unlikely to be accurate",
+            average_die_power,
+            PLAUSIBLE_DIE_POWER.start(),
+            PLAUSIBLE_DIE_POWER.end()
+        );
+    }
+    log::info!(
+        "Average die power is {} W ({})",
+        average_die_power,
+        average_die_power_source
+    );
+
+    let idle_power = args.idle_power.unwrap_or(0.0);
+    if idle_power >= average_die_power {
+        bail!(
+            "Idle power ({} W) must be less than the average die power ({} W)",
+            idle_power,
+            average_die_power
+        );
+    }
+    if args.idle_power.is_some() {
+        log::info!("Idle power is {} W", idle_power);
+    }
 
-    let num_physical_cores = num_cpus::get_physical();
-    let average_core_power = average_die_power / (num_physical_cores as f64);
+    let average_core_power = (average_die_power - idle_power) / (num_physical_cores as f64);
+    let per_core_power: HashMap<usize, f64> = args.per_core_power.iter().copied().collect();
+    for (cpu, power) in &args.per_core_power {
+        log::info!("CPU {} power is {} W", cpu, power);
+    }
 
-    let cpu_time = Family::<Labels, Counter>::default();
-    let energy = Family::<Labels, Counter>::default();
+    let batteries = match args.battery_power {
+        true => battery::Batteries::discover(),
+        false => None,
+    };
+    let host_power = Family::<HostLabels, Gauge<f64, AtomicU64>>::default();
 
-    let clk_tck = sysconf::raw::sysconf(sysconf::raw::SysconfVariable::ScClkTck).unwrap();
-    let jiffy_in_seconds = 1.0 / (clk_tck as f64);
-    log::info!("1 jiffy is {} seconds", jiffy_in_seconds);
+    let mut energy_meter = energy::discover(args.collection_interval);
+    // Energy meters are read for the first time when they are discovered
+    let mut last_energy_read = Instant::now();
+    let energy_source = match &energy_meter {
+        Some(meter) => meter.name(),
+        None => "tdp",
+    };
+    log::info!(
+        "Using {} for energy measurement on {}",
+        energy_source,
+        std::env::consts::ARCH
+    );
+
+    let process_metrics =
+        ProcessMetrics::new(args.enable_histograms, args.energy_unit, args.energy_metric);
+    let host_energy = Family::<HostLabels, Counter<f64, AtomicU64>>::default();
+    let host_idle_energy = Counter::<f64, AtomicU64>::default();
+    let tracked_cpu_time = Counter::<f64, AtomicU64>::default();
+    let tracked_energy = Counter::<f64, AtomicU64>::default();
+    let tracked_processes = Gauge::<i64, AtomicI64>::default();
+    let mut persistent_energy = args.state_file.as_deref().map(|path| {
+        state::PersistentEnergy::load(path, args.energy_unit, args.state_file_interval)
+    });
+    let collection_duration = Gauge::<f64, AtomicU64>::default();
+    let collection_errors = Counter::<u64>::default();
+    let collection_overruns = Counter::<u64>::default();
+    let self_cpu_time = Counter::<f64, AtomicU64>::default();
+    let self_memory_resident = Gauge::<i64, AtomicI64>::default();
+    let last_collection_timestamp = Gauge::<f64, AtomicU64>::default();
+
+    let mut gpus = match args.enable_gpu {
+        true => gpu::Gpus::discover(),
+        false => None,
+    };
+
+    let mut series_limit = args
+        .max_series
+        .map(|max_series| limit::SeriesLimit::new(max_series as usize));
+
+    let mut remote_write = match &args.remote_write_url {
+        Some(url) => Some(remote_write::RemoteWrite::new(
+            url,
+            args.remote_write_username
+                .as_ref()
+                .map(|username| remote_write::BasicAuth {
+                    username: username.clone(),
+                    password: args.remote_write_password.clone(),
+                }),
+            args.remote_write_interval,
+        )?),
+        None => None,
+    };
+
+    let mut network = match args.enable_network {
+        true => Some(network::NetworkMetrics::new(&args.proc_path)?),
+        false => None,
+    };
+
+    if args.per_thread && args.cpu_time_source != CpuTimeSource::Proc {
+        bail!("--per-thread requires --cpu-time-source proc");
+    }
+    let mut snapshot_source = args.cpu_time_source.snapshot_source(
+        &args.proc_path,
+        args.clock_ticks_per_second,
+        args.per_thread,
+    )?;
+    let mut thread_metrics = args.per_thread.then(threads::ThreadMetrics::default);
+    log::info!("Using {} for CPU time", args.cpu_time_source.name());
+
+    let hostname = match &args.hostname_override {
+        Some(hostname) => {
+            log::info!("Using hostname \"{}\" (override)", hostname);
+            hostname.clone()
+        }
+        None => {
+            let hostname = sys.host_name().unwrap_or_else(|| "unknown".into());
+            log::info!("Using hostname \"{}\" (system)", hostname);
+            hostname
+        }
+    };
+
+    let pod = kubernetes::PodMetadata::from_env();
 
     {
         let mut registry = watcher.metrics_registry();
-        let registry =
-            registry.sub_registry_with_label(("hostname".into(), sys.host_name().unwrap().into()));
+        let mut registry =
+            registry.sub_registry_with_label(("hostname".into(), hostname.clone().into()));
+        for (name, value) in pod.labels() {
+            registry = registry.sub_registry_with_label((name.into(), value.to_string().into()));
+        }
+        for (name, value) in &args.label {
+            registry =
+                registry.sub_registry_with_label((name.clone().into(), value.clone().into()));
+        }
 
-        let system = Info::new(vec![
+        let mut system = vec![
             (
                 "os".to_string(),
                 sys.name().unwrap_or_else(|| "unknown".into()),
@@ -117,9 +1392,34 @@ async fn main() {
                 "kernel_version".to_string(),
                 sys.kernel_version().unwrap_or_else(|| "unknown".into()),
             ),
-            ("jiffy_in_seconds".to_string(), jiffy_in_seconds.to_string()),
+        ];
+        system.push((
+            "collection_interval".to_string(),
+            humantime::format_duration(args.collection_interval).to_string(),
+        ));
+        system.push((
+            "cpu_time_source".to_string(),
+            args.cpu_time_source.name().to_string(),
+        ));
+        system.extend(snapshot_source.info());
+        registry.register("system", "Host OS information", Info::new(system));
+
+        let build = Info::new(vec![
+            ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            (
+                "git_commit".to_string(),
+                env!("YUUGI_GIT_COMMIT").to_string(),
+            ),
+            (
+                "rustc_version".to_string(),
+                env!("YUUGI_RUSTC_VERSION").to_string(),
+            ),
+            (
+                "build_timestamp".to_string(),
+                env!("YUUGI_BUILD_TIMESTAMP").to_string(),
+            ),
         ]);
-        registry.register("system", "Host OS information", system);
+        registry.register("build", "Build information of yuugi", build);
 
         let cpu = Info::new(vec![
             (
@@ -134,63 +1434,855 @@ async fn main() {
                 "average_die_power".to_string(),
                 average_die_power.to_string(),
             ),
+            (
+                "average_die_power_source".to_string(),
+                average_die_power_source.to_string(),
+            ),
+            (
+                "power_db_entry".to_string(),
+                power_db_entry
+                    .map(|(entry, _)| entry.to_string())
+                    .unwrap_or_default(),
+            ),
             (
                 "average_core_power".to_string(),
                 average_core_power.to_string(),
             ),
+            ("idle_power".to_string(), idle_power.to_string()),
             (
                 "num_physical_cores".to_string(),
                 num_physical_cores.to_string(),
             ),
+            ("energy_source".to_string(), energy_source.to_string()),
+            ("arch".to_string(), std::env::consts::ARCH.to_string()),
         ]);
         registry.register("cpu", "Host CPU information", cpu);
 
+        process_metrics.register(registry);
+        registry.register(
+            "tracked_processes",
+            "Number of processes exported in the last collection",
+            tracked_processes.clone(),
+        );
         registry.register_with_unit(
-            "cpu_time",
-            "Total CPU time spent executing process",
+            "tracked_cpu_time",
+            "Total CPU time of all tracked processes",
             Unit::Seconds,
-            cpu_time.clone(),
+            tracked_cpu_time.clone(),
+        );
+        registry.register_with_unit(
+            "tracked_energy",
+            "Total energy attributed to all tracked processes",
+            Unit::Other(args.energy_unit.name().to_string()),
+            tracked_energy.clone(),
+        );
+        registry.register_with_unit(
+            "yuugi_process_cpu",
+            "Total CPU time used by yuugi itself",
+            Unit::Seconds,
+            self_cpu_time.clone(),
         );
-
         registry.register_with_unit(
-            "energy",
-            "Total energy time spent executing process",
-            Unit::Other("watt_hours".to_string()),
-            energy.clone(),
+            "yuugi_process_resident_memory",
+            "Resident memory used by yuugi itself",
+            Unit::Bytes,
+            self_memory_resident.clone(),
         );
+        if let Some(server) = &server {
+            server.register(registry);
+        }
+        registry.register_with_unit(
+            "collection_duration",
+            "Time taken by the last collection",
+            Unit::Seconds,
+            collection_duration.clone(),
+        );
+        registry.register(
+            "collection_errors",
+            "Total number of unexpected failures to collect a process or read the process list",
+            collection_errors.clone(),
+        );
+        registry.register(
+            "collection_overruns",
+            "Total number of collections that took longer than the collection interval",
+            collection_overruns.clone(),
+        );
+        registry.register(
+            "collector_up",
+            "Whether the last successful collection is more recent than the staleness threshold (1) or not (0)",
+            collector_up.clone(),
+        );
+        registry.register_with_unit(
+            "last_collection_timestamp",
+            "Time of the last successful collection since the Unix epoch",
+            Unit::Seconds,
+            last_collection_timestamp.clone(),
+        );
+
+        if let Some(gpus) = &gpus {
+            gpus.register(registry);
+        }
+        if let Some(network) = &network {
+            network.register(registry);
+        }
+        if let Some(thread_metrics) = &thread_metrics {
+            thread_metrics.register(registry);
+        }
+        if let Some(series_limit) = &series_limit {
+            series_limit.register(registry);
+        }
+        if let Some(persistent_energy) = &persistent_energy {
+            persistent_energy.register(registry);
+        }
+        if let Some(remote_write) = &remote_write {
+            remote_write.register(registry);
+        }
+
+        // Measured total energy, for comparison with the sum of the per process values
+        if let Some(meter) = &energy_meter {
+            registry.register_with_unit(
+                "host_energy",
+                meter.description(),
+                Unit::Joules,
+                host_energy.clone(),
+            );
+        }
+
+        if batteries.is_some() {
+            registry.register_with_unit(
+                "host_power",
+                "Power drawn from battery, only present while discharging",
+                Unit::Other("watts".to_string()),
+                host_power.clone(),
+            );
+        }
+
+        if args.idle_power.is_some() {
+            registry.register_with_unit(
+                "host_idle_energy",
+                "Total energy consumed by the CPU while idle, which is not attributed to any process",
+                Unit::Joules,
+                host_idle_energy.clone(),
+            );
+        }
+    }
+
+    let otlp = match &args.otlp_endpoint {
+        Some(endpoint) => Some(otlp::OtlpExporter::new(
+            endpoint,
+            args.collection_interval,
+            &hostname,
+            &pod,
+            &args.label,
+            &process_metrics,
+        )?),
+        None => None,
+    };
+
+    let statsd = match &args.statsd_address {
+        Some(address) => Some(statsd::StatsdExporter::new(
+            address,
+            &hostname,
+            &pod,
+            &args.label,
+            process_metrics.descriptors(),
+        )?),
+        None => None,
+    };
+
+    let pushgateway = match &args.pushgateway_url {
+        Some(url) => Some(pushgateway::Pushgateway::new(
+            url,
+            &args.pushgateway_job,
+            args.pushgateway_instance.as_ref().unwrap_or(&hostname),
+        )?),
+        None => None,
+    };
+
+    let self_pid = Pid::from_u32(std::process::id());
+    let mut pidfiles =
+        (!args.pidfile.is_empty()).then(|| pidfile::PidFiles::new(args.pidfile.clone()));
+
+    match args.collect_on_scrape {
+        true => log::info!("Collecting on scrape"),
+        false => log::info!(
+            "Collecting every {}",
+            humantime::format_duration(args.collection_interval)
+        ),
+    }
+    if args.jitter_every_collection && args.collection_jitter >= args.collection_interval {
+        bail!("--collection-jitter must be less than the collection interval with --jitter-every-collection");
     }
+    let mut jitter_rng = StdRng::seed_from_u64(labels::fnv1a(&hostname));
+    let first_collection = match args.once {
+        true => Instant::now(),
+        false => {
+            let jitter = args.collection_jitter.mul_f64(jitter_rng.random());
+            if !jitter.is_zero() {
+                log::info!(
+                    "Delaying the first collection by {}",
+                    humantime::format_duration(jitter)
+                );
+            }
+            Instant::now() + jitter
+        }
+    };
+    let mut collection_interval = time::interval_at(first_collection, args.collection_interval);
+    // The default is to catch up on every missed tick in a burst, which on a host too loaded to
+    // collect within the interval would collect continuously
+    collection_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut history = history::History::default();
+    let mut process_details = cache::ProcessCache::default();
+    // Start time and number of consecutive collections each process has been seen in, until it
+    // has been seen in enough to be exported
+    let mut observations = HashMap::<Pid, (u64, u32)>::new();
+    let mut previous_self_cpu_time = 0.0;
+
+    let mut shutdown_signals = signals::ShutdownSignals::new()?;
+
+    let mut top = match show_top {
+        true => Some(top::Top::start(args.energy_unit)?),
+        false => None,
+    };
 
-    let mut collection_interval = time::interval(Duration::from_millis(args.collection_interval));
+    // When collecting on scrape, there is still an initial collection at startup so that
+    // counters are up to date for the first scrape
+    let mut last_collection: Option<Instant> = None;
+    let mut last_tick: Option<Instant> = None;
+    let mut pending_requests = Vec::new();
+    // Current backoff and when to next try collecting, after the process list could not be read
+    let mut refresh_backoff: Option<(Duration, Instant)> = None;
 
     loop {
-        collection_interval.tick().await;
+        tokio::select! {
+            scheduled = collection_interval.tick(), if !args.collect_on_scrape || last_collection.is_none() => {
+                let skipped = (scheduled.elapsed().as_secs_f64()
+                    / args.collection_interval.as_secs_f64()) as u64;
+                if skipped > 0 {
+                    log::debug!(
+                        "Collection is running {:?} late, skipping {} collections",
+                        scheduled.elapsed(),
+                        skipped
+                    );
+                }
+                if args.jitter_every_collection && last_collection.is_some() {
+                    time::sleep(args.collection_jitter.mul_f64(jitter_rng.random())).await;
+                }
+            }
+            Some(request) = collection_requests.recv() => {
+                if last_collection.is_some_and(|last| last.elapsed() < args.scrape_cache_ttl) {
+                    request.send(()).ok();
+                    continue;
+                }
+                pending_requests.push(request);
+            }
+            signal = shutdown_signals.recv() => {
+                log::info!("Received {}, shutting down", signal);
+                break;
+            }
+            _ = top::quit(&mut top) => {
+                log::info!("Quit, shutting down");
+                break;
+            }
+        }
+
+        if refresh_backoff.is_some_and(|(_, retry_at)| Instant::now() < retry_at) {
+            // Scrapes are served the last values rather than waiting for the backoff
+            for request in pending_requests.drain(..) {
+                request.send(()).ok();
+            }
+            continue;
+        }
 
         log::info!("Refreshing metrics");
-        sys.refresh_all();
+        let collection_start = Instant::now();
+        // Only process information is needed after startup, and only the CPU usage, disk usage
+        // and user parts of that (name, cmdline and memory are always refreshed).
+        // With ~500 processes on a single core VM this takes ~7 ms per refresh, compared to
+        // ~11 ms for refresh_all() and ~10 ms for refresh_processes().
+        sys.refresh_processes_specifics(
+            ProcessRefreshKind::new()
+                .with_cpu()
+                .with_disk_usage()
+                .with_user(),
+        );
+
+        // The list always contains at least yuugi itself, unless /proc could not be read at all
+        // (e.g. in a restricted container during startup)
+        if sys.processes().is_empty() {
+            if args.once {
+                bail!("Failed to read the process list");
+            }
+            let backoff = match refresh_backoff {
+                Some((backoff, _)) => (backoff * 2).min(args.max_refresh_backoff),
+                None => args.collection_interval.min(args.max_refresh_backoff),
+            };
+            log::warn!(
+                "Failed to read the process list, retrying in {}",
+                humantime::format_duration(backoff)
+            );
+            collection_errors.inc();
+            refresh_backoff = Some((backoff, Instant::now() + backoff));
+            for request in pending_requests.drain(..) {
+                request.send(()).ok();
+            }
+            continue;
+        }
+        if refresh_backoff.take().is_some() {
+            log::info!("Read the process list, resuming collection");
+        }
+
+        let first_tick = history.is_empty();
+
+        let pidfile_processes = pidfiles.as_mut().map(|pidfiles| pidfiles.processes(&sys));
 
+        let mut samples = Vec::new();
+        let mut self_snapshot = None;
+        let mut observed = HashMap::new();
         for (pid, process) in sys.processes() {
-            let labels = Labels {
-                process_name: process.name().to_string(),
-                cmdline: process.cmd().join(" "),
-                pid: pid.to_string(),
+            let details = process_details.get_or_read(pid, process.start_time(), || {
+                read_process_details(&args, pid)
+            });
+            if is_excluded(
+                &args,
+                self_pid,
+                pidfile_processes.as_ref(),
+                pid,
+                process,
+                details,
+            ) {
+                continue;
+            }
+
+            // Processes that were exported in the previous collection have been seen enough
+            let exported = history
+                .get(pid)
+                .is_some_and(|previous| previous.snapshot.start_time == process.start_time());
+            if args.min_observations > 1 && !exported {
+                let count = match observations.get(pid) {
+                    Some((start_time, count)) if *start_time == process.start_time() => count + 1,
+                    _ => 1,
+                };
+                observed.insert(*pid, (process.start_time(), count));
+                if count < args.min_observations {
+                    continue;
+                }
+            }
+
+            let mut labels = match process_labels(&args, &sys, pid, process, details) {
+                Some(labels) => labels,
+                None => continue,
             };
 
-            let run_time = (get_process_jiffies(pid) as f64) * jiffy_in_seconds;
-            log::trace!("PID {} total CPU time = {}", pid, run_time);
+            history.check_reused(&process_metrics, pid, process.start_time());
+
+            if let Some(snapshot) = snapshot_source.collect(pid, process) {
+                if *pid == self_pid {
+                    self_snapshot = Some(snapshot.clone());
+                }
+                // Not remembered for the next collection, so that all of the CPU time of a process
+                // is counted once it crosses the threshold
+                if snapshot.cpu_time < args.min_cpu_seconds {
+                    continue;
+                }
+                // Only known once the process has been collected
+                if args.sched_policy_label && labels.pid.is_some() {
+                    labels.sched_policy = snapshot.sched_policy.clone();
+                }
+                samples.push((*pid, labels, snapshot));
+            }
+        }
+        // yuugi itself is collected even when it is not exported, but only once per collection
+        if self_snapshot.is_none() {
+            if let Some(process) = sys.process(self_pid) {
+                self_snapshot = snapshot_source.collect(&self_pid, process);
+            }
+        }
+        if let Some(snapshot) = self_snapshot {
+            self_cpu_time.inc_by((snapshot.cpu_time - previous_self_cpu_time).max(0.0));
+            previous_self_cpu_time = snapshot.cpu_time;
+            self_memory_resident.set(snapshot.memory_resident as i64);
+        }
+        snapshot_source.end_collection();
+        process_details.end_collection();
+        observations = observed;
+        if args.dedupe_by_cmdline {
+            labels::dedupe_by_cmdline(samples.iter_mut().map(|(_, labels, _)| labels).collect());
+        }
+        tracked_processes.set(samples.len() as i64);
+        collection_errors.inc_by(snapshot_source.take_errors());
+
+        // When it can be measured (e.g. by RAPL) the CPU energy for this interval is apportioned
+        // between processes by their share of the CPU time used in the same interval.
+        // There is no previous sample on the first tick, so nothing is apportioned then.
+        let now = Instant::now();
+        let idle_energy = idle_power * now.duration_since(last_energy_read).as_secs_f64();
+        last_energy_read = now;
+
+        // Wall time since the previous tick, measured as ticks can be delayed by a slow collection
+        // (and are irregular when collecting on scrape), so may differ from the collection
+        // interval. There is no previous tick on the first tick.
+        let elapsed = last_tick.map(|last_tick| now.duration_since(last_tick).as_secs_f64());
+        last_tick = Some(now);
+        host_idle_energy.inc_by(idle_energy);
+
+        let measured_energy = energy_meter.as_mut().map(|meter| {
+            let reading = meter.read_energy();
+            for (labels, energy) in &reading.domains {
+                host_energy.get_or_create(labels).inc_by(*energy);
+            }
+            // Idle energy is split between sockets by their share of the measured energy
+            let idle_share = match reading.cpu > 0.0 {
+                true => (idle_energy / reading.cpu).min(1.0),
+                false => 0.0,
+            };
+            let socket_energy: HashMap<usize, f64> = reading
+                .sockets
+                .iter()
+                .map(|(socket, energy)| (*socket, energy * (1.0 - idle_share)))
+                .collect();
+            ((reading.cpu - idle_energy).max(0.0), socket_energy)
+        });
+
+        let gpu_energy = gpus.as_mut().map(|gpus| gpus.collect());
+
+        // Used in place of the average die power while running on battery
+        let mut battery_power = None;
+        for (name, power) in batteries
+            .iter()
+            .flat_map(|batteries| batteries.read_power())
+        {
+            let labels = HostLabels(vec![("battery".to_string(), name.to_string())]);
+            match power {
+                Some(power) => {
+                    host_power.get_or_create(&labels).set(power);
+                    *battery_power.get_or_insert(0.0) += power;
+                }
+                None => {
+                    host_power.remove(&labels);
+                }
+            }
+        }
+        let default_core_power = match battery_power {
+            Some(power) => (power - idle_power).max(0.0) / (num_physical_cores as f64),
+            None => average_core_power,
+        };
+
+        // CPU time used by each process since the previous tick, processes that were not present
+        // at the previous tick contribute all of their CPU time.
+        // Counters are incremented by these deltas so that they remain monotonic when processes
+        // come and go from an aggregated series.
+        let interval_cpu_time: Vec<f64> = samples
+            .iter()
+            .map(|(pid, _, snapshot)| {
+                let previous_cpu_time = history
+                    .get(pid)
+                    .map(|previous| previous.snapshot.cpu_time)
+                    .unwrap_or(0.0);
+                (snapshot.cpu_time - previous_cpu_time).max(0.0)
+            })
+            .collect();
+        let total_interval_cpu_time: f64 = interval_cpu_time.iter().sum();
+
+        // Where energy is measured per socket (e.g. RAPL on a multi socket system) it is
+        // apportioned within each socket, between the processes that last ran on it. This needs
+        // the socket of every process to be known, otherwise the total is apportioned.
+        let sockets: Option<Vec<usize>> = match (&energy_meter, &measured_energy) {
+            (Some(meter), Some((_, socket_energy))) if !socket_energy.is_empty() => samples
+                .iter()
+                .map(|(_, _, snapshot)| {
+                    snapshot
+                        .last_cpu
+                        .and_then(|cpu| meter.cpu_socket(cpu))
+                        .filter(|socket| socket_energy.contains_key(socket))
+                })
+                .collect(),
+            _ => None,
+        };
+        let mut socket_interval_cpu_time = HashMap::<usize, f64>::new();
+        for (socket, cpu_time) in sockets.iter().flatten().zip(&interval_cpu_time) {
+            *socket_interval_cpu_time.entry(*socket).or_default() += cpu_time;
+        }
+
+        let mut totals = HashMap::<Labels, Sample>::new();
+        for (index, ((pid, labels, snapshot), cpu_time)) in
+            samples.iter().zip(interval_cpu_time).enumerate()
+        {
+            log::trace!("PID {} total CPU time = {}", pid, snapshot.cpu_time);
+
+            // Other cumulative values are handled in the same way as CPU time
+            let previous = history.get(pid).map(|p| &p.snapshot);
+            // Rates are only known over a single interval
+            let consecutive = history
+                .get(pid)
+                .is_some_and(|previous| previous.missed == 0);
+            let interval_count =
+                |current: u64, previous: Option<u64>| current.saturating_sub(previous.unwrap_or(0));
+            let interval_optional_count = |current: Option<u64>, previous: Option<u64>| {
+                current.map(|current| interval_count(current, previous))
+            };
+            let interval_time = |current: Option<f64>, previous: Option<f64>| {
+                current.map(|current| (current - previous.unwrap_or(0.0)).max(0.0))
+            };
+
+            let energy_joules = match &measured_energy {
+                Some((measured_energy, socket_energy)) => {
+                    let (measured_energy, total_interval_cpu_time) = match &sockets {
+                        Some(sockets) => (
+                            socket_energy[&sockets[index]],
+                            socket_interval_cpu_time[&sockets[index]],
+                        ),
+                        None => (*measured_energy, total_interval_cpu_time),
+                    };
+                    if first_tick || total_interval_cpu_time == 0.0 {
+                        0.0
+                    } else {
+                        let share = cpu_time / total_interval_cpu_time;
+                        measured_energy * share
+                    }
+                }
+                None => {
+                    let core_power = snapshot
+                        .last_cpu
+                        .and_then(|cpu| per_core_power.get(&cpu))
+                        .copied()
+                        .unwrap_or(default_core_power);
+                    cpu_time * core_power
+                }
+            };
+            let energy = args.energy_unit.convert_joules(energy_joules);
+
+            // The energy of a new process is for all CPU time since it started, so power is only
+            // known for processes that were also present at the previous tick
+            let power = match elapsed {
+                Some(elapsed) if consecutive && elapsed > 0.0 => Some(energy_joules / elapsed),
+                _ => None,
+            };
+
+            // Averaged over the interval the CPU time was used in, for processes that were also
+            // present at the previous tick. Usage is not known for the first collection (or the
+            // first collection of a process), rather than being reported as 0.
+            let cpu_usage = match elapsed {
+                Some(elapsed) if consecutive && elapsed > 0.0 => Some(cpu_time / elapsed),
+                _ => None,
+            };
+
+            totals.entry(labels.clone()).or_default().add(&Sample {
+                cpu_time,
+                cpu_user_time: interval_time(
+                    snapshot.cpu_user_time,
+                    previous.and_then(|p| p.cpu_user_time),
+                ),
+                cpu_kernel_time: interval_time(
+                    snapshot.cpu_kernel_time,
+                    previous.and_then(|p| p.cpu_kernel_time),
+                ),
+                energy,
+                power,
+                cpu_usage,
+                memory_resident: snapshot.memory_resident,
+                memory_virtual: snapshot.memory_virtual,
+                memory_swap: snapshot.memory_swap,
+                threads: snapshot.num_threads,
+                open_file_descriptors: snapshot.open_file_descriptors,
+                nice: snapshot.nice,
+                start_time: Some(snapshot.start_time),
+                disk_read: interval_count(
+                    snapshot.disk_read_bytes,
+                    previous.map(|p| p.disk_read_bytes),
+                ),
+                disk_write: interval_count(
+                    snapshot.disk_written_bytes,
+                    previous.map(|p| p.disk_written_bytes),
+                ),
+                voluntary_context_switches: interval_optional_count(
+                    snapshot.voluntary_context_switches,
+                    previous.and_then(|p| p.voluntary_context_switches),
+                ),
+                involuntary_context_switches: interval_optional_count(
+                    snapshot.involuntary_context_switches,
+                    previous.and_then(|p| p.involuntary_context_switches),
+                ),
+                minor_page_faults: interval_optional_count(
+                    snapshot.minor_page_faults,
+                    previous.and_then(|p| p.minor_page_faults),
+                ),
+                major_page_faults: interval_optional_count(
+                    snapshot.major_page_faults,
+                    previous.and_then(|p| p.major_page_faults),
+                ),
+                block_io_delay: interval_time(
+                    snapshot.block_io_delay,
+                    previous.and_then(|p| p.block_io_delay),
+                ),
+                gpu_energy: gpu_energy.as_ref().map(|energy| {
+                    args.energy_unit
+                        .convert_joules(energy.get(pid).copied().unwrap_or(0.0))
+                }),
+                states: snapshot
+                    .state
+                    .iter()
+                    .map(|state| (state.clone(), 1))
+                    .collect(),
+            });
+        }
+
+        if args.once && totals.is_empty() {
+            bail!("No processes were collected");
+        }
+
+        // Summed before the series limit is applied, so that the totals do not depend on which
+        // series are exported
+        for sample in totals.values() {
+            tracked_cpu_time.inc_by(sample.cpu_time);
+            tracked_energy.inc_by(sample.energy);
+        }
+        if let Some(persistent_energy) = &mut persistent_energy {
+            for (labels, sample) in &totals {
+                persistent_energy.add(labels, sample.energy);
+            }
+        }
+
+        if let Some(series_limit) = &mut series_limit {
+            series_limit.apply(&mut totals);
+        }
+
+        if let Some(network) = &mut network {
+            network.collect(
+                samples
+                    .iter()
+                    .filter(|(_, labels, _)| totals.contains_key(labels))
+                    .map(|(pid, labels, _)| (pid, labels)),
+            );
+        }
+        if let Some(thread_metrics) = &mut thread_metrics {
+            thread_metrics.update(
+                samples
+                    .iter()
+                    .filter(|(_, labels, _)| totals.contains_key(labels))
+                    .map(|(_, labels, snapshot)| (labels, snapshot)),
+            );
+        }
+
+        for (labels, sample) in &totals {
+            process_metrics.update(labels, sample);
+        }
+        if let Some(statsd) = &statsd {
+            statsd.send(&totals);
+        }
+
+        history.update(&process_metrics, samples, totals.into_keys());
 
-            // TODO: this is dropping sub second precision
-            cpu_time
-                .get_or_create(&labels)
-                .inner()
-                .store(run_time as u64, Ordering::Relaxed);
+        if let Some(top) = &top {
+            top.update(process_metrics.snapshot());
+        }
 
-            let e = (run_time * average_core_power) / 3600.0;
+        readiness.mark_ready(ReadinessConditions::FirstCollectionComplete);
+        collector_up.collected();
+        last_collection_timestamp.set(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |t| t.as_secs_f64()),
+        );
 
-            // TODO: this is dropping sub Wh precision
-            energy
-                .get_or_create(&labels)
-                .inner()
-                .store(e as u64, Ordering::Relaxed);
+        let duration = collection_start.elapsed();
+        collection_duration.set(duration.as_secs_f64());
+        if !args.collect_on_scrape && duration > args.collection_interval {
+            log::debug!(
+                "Collection took {:?}, longer than the collection interval",
+                duration
+            );
+            collection_overruns.inc();
         }
+
+        // Scrapes that arrived during this collection are served by it
+        last_collection = Some(Instant::now());
+        while let Ok(request) = collection_requests.try_recv() {
+            pending_requests.push(request);
+        }
+        for request in pending_requests.drain(..) {
+            request.send(()).ok();
+        }
+
+        if let Some(pushgateway) = &pushgateway {
+            if args.pushgateway_every_collection {
+                pushgateway.push_in_background(encoding::encode_text(&watcher.metrics_registry())?);
+            }
+        }
+
+        if let Some(remote_write) = &mut remote_write {
+            if let Err(e) = remote_write.collected(&watcher.metrics_registry()) {
+                log::warn!("{:#}", e);
+            }
+        }
+
+        if let Some(persistent_energy) = &mut persistent_energy {
+            if let Err(e) = persistent_energy.collected() {
+                log::warn!("{:#}", e);
+            }
+        }
+
+        if args.once {
+            // The first collection only provides the previous values that CPU usage and power are
+            // calculated from
+            if first_tick {
+                continue;
+            }
+            print!("{}", encoding::encode_text(&watcher.metrics_registry())?);
+            break;
+        }
+    }
+
+    // Restore the terminal before anything else is logged
+    drop(top);
+
+    // Scrapes waiting for a collection are served the last values
+    drop(collection_requests);
+    drop(pending_requests);
+
+    // Allow any scrape that is in progress to complete before exiting
+    if let Some(server) = server {
+        server.stop().await?;
+    }
+
+    if let Some(otlp) = otlp {
+        otlp.shutdown()?;
+    }
+
+    if let Some(persistent_energy) = &mut persistent_energy {
+        persistent_energy.save()?;
+    }
+
+    // Push the final values, this is the last chance for those of a short lived job to be seen
+    if let Some(pushgateway) = pushgateway {
+        let metrics = encoding::encode_text(&watcher.metrics_registry())?;
+        pushgateway.push_final(metrics).await?;
+    }
+
+    log::info!("Shutdown complete");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kagiyama::prometheus::registry::Registry;
+
+    #[test]
+    fn power() {
+        assert_eq!(parse_power("15W").unwrap(), 15.0);
+        assert_eq!(parse_power("15").unwrap(), 15.0);
+        assert_eq!(parse_power(" 12.5 W ").unwrap(), 12.5);
+        assert_eq!(parse_power("0.5kW").unwrap(), 500.0);
+        for invalid in [
+            "-15", "-15W", "0", "0W", "", "W", "kW", "fifteen", "15 Watts", "15kWh", "NaN", "inf",
+        ] {
+            assert!(parse_power(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn physical_cores_reported_as_zero() {
+        assert_eq!(physical_core_count(4, 8), 4);
+        assert_eq!(physical_core_count(0, 8), 8);
+        assert_eq!(physical_core_count(0, 0), 1);
+    }
+
+    /// Encodes the series yuugi itself would be exported as with the given arguments.
+    fn encode_own_process(args: &[&str]) -> String {
+        let args = Cli::try_parse_from(args).unwrap();
+        let pid = sysinfo::get_current_pid().unwrap();
+        let mut sys = System::new();
+        sys.refresh_process(pid);
+        let details = read_process_details(&args, &pid);
+        let labels =
+            process_labels(&args, &sys, &pid, sys.process(pid).unwrap(), &details).unwrap();
+
+        let metrics = ProcessMetrics::new(false, EnergyUnit::WattHours, EnergyMetric::Energy);
+        metrics.update(&labels, &Sample::default());
+        let mut registry = Registry::default();
+        metrics.register(&mut registry);
+        encoding::encode_text(&registry).unwrap()
+    }
+
+    #[test]
+    fn no_cmdline() {
+        assert!(encode_own_process(&["yuugi"]).contains("cmdline="));
+
+        let text = encode_own_process(&["yuugi", "--no-cmdline"]);
+        assert!(!text.contains("cmdline="), "{}", text);
+        assert!(text.contains("process_name="), "{}", text);
+    }
+
+    #[test]
+    fn systemd_unit_label_is_opt_in() {
+        let args = Cli::try_parse_from(["yuugi"]).unwrap();
+        assert!(
+            read_process_details(&args, &sysinfo::get_current_pid().unwrap())
+                .cgroup
+                .is_none()
+        );
+        let text = encode_own_process(&["yuugi"]);
+        assert!(!text.contains("systemd_unit="), "{}", text);
+    }
+
+    #[test]
+    fn exclude_cgroup() {
+        let args = Cli::try_parse_from(["yuugi", "--exclude-cgroup", "^/kubepods"]).unwrap();
+        let pid = sysinfo::get_current_pid().unwrap();
+        let mut sys = System::new();
+        sys.refresh_process(pid);
+        let process = sys.process(pid).unwrap();
+        let details = |path: &str| ProcessDetails {
+            cgroup: Some(procfs::Cgroup {
+                path: path.to_string(),
+                container_id: None,
+                pod_uid: None,
+                systemd_unit: None,
+            }),
+            env: Vec::new(),
+        };
+        let excluded = |details: &ProcessDetails| {
+            is_excluded(&args, Pid::from(1), None, &pid, process, details)
+        };
+
+        assert!(excluded(&details("/kubepods/burstable/pod1234")));
+        assert!(!excluded(&details("/system.slice/nginx.service")));
+        // Not excluded if the cgroup cannot be read
+        assert!(!excluded(&ProcessDetails {
+            cgroup: None,
+            env: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn labels_from_environment() {
+        let proc_path = std::env::temp_dir().join(format!("yuugi-environ-{}", std::process::id()));
+        let pid = Pid::from(42);
+        std::fs::create_dir_all(proc_path.join("42")).unwrap();
+        std::fs::write(
+            proc_path.join("42/environ"),
+            "HOME=/root\0APP=check\tout\0TEAM=\0APP=second\0",
+        )
+        .unwrap();
+        let args = Cli::try_parse_from([
+            "yuugi",
+            "--proc-path",
+            proc_path.to_str().unwrap(),
+            "--label-from-env",
+            "APP,TEAM,REGION",
+        ])
+        .unwrap();
+
+        let details = read_process_details(&args, &pid);
+        // Unreadable, e.g. of an exited process
+        let unreadable = read_process_details(&args, &Pid::from(43));
+        std::fs::remove_dir_all(&proc_path).ok();
+
+        assert_eq!(
+            details.env,
+            vec![("app".to_string(), "check out".to_string())]
+        );
+        assert!(unreadable.env.is_empty());
     }
 }
@@ -0,0 +1,897 @@
+use crate::labels::{self, Labels};
+use kagiyama::prometheus::{
+    encoding::{EncodeLabelSet, LabelSetEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::{Atomic, Gauge},
+        histogram::Histogram,
+    },
+    registry::{Metric, Registry, Unit},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::Add,
+    sync::{atomic::AtomicU64, Arc, RwLock},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// Describes a per process metric.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Descriptor {
+    pub(crate) name: &'static str,
+    pub(crate) help: &'static str,
+    /// Unit, as used in Prometheus metric names
+    pub(crate) unit: Option<&'static str>,
+    pub(crate) kind: Kind,
+}
+
+impl Descriptor {
+    fn with_energy_unit(&self, energy_unit: EnergyUnit) -> Self {
+        Self {
+            unit: Some(energy_unit.name()),
+            ..*self
+        }
+    }
+}
+
+/// Unit in which energy is exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum EnergyUnit {
+    #[default]
+    WattHours,
+    Joules,
+    KilowattHours,
+}
+
+impl EnergyUnit {
+    /// Name of the unit, as used in Prometheus metric names.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::WattHours => "watt_hours",
+            Self::Joules => "joules",
+            Self::KilowattHours => "kilowatt_hours",
+        }
+    }
+
+    /// Converts an energy in Joules to this unit.
+    pub(crate) fn convert_joules(&self, joules: f64) -> f64 {
+        match self {
+            Self::WattHours => joules / 3600.0,
+            Self::Joules => joules,
+            Self::KilowattHours => joules / 3.6e6,
+        }
+    }
+}
+
+/// Which of cumulative energy and power are exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum EnergyMetric {
+    /// Only the energy counter
+    #[default]
+    Energy,
+    /// Only the power gauge
+    Power,
+    /// Both the energy counter and the power gauge
+    Both,
+}
+
+impl EnergyMetric {
+    fn energy(&self) -> bool {
+        matches!(self, Self::Energy | Self::Both)
+    }
+
+    fn power(&self) -> bool {
+        matches!(self, Self::Power | Self::Both)
+    }
+}
+
+const CPU_TIME: Descriptor = Descriptor {
+    name: "cpu_time",
+    help: "Total CPU time spent executing process",
+    unit: Some("seconds"),
+    kind: Kind::Counter,
+};
+
+// Only available on Linux, these sum to the CPU time
+const CPU_USER_TIME: Descriptor = Descriptor {
+    name: "cpu_user_time",
+    help: "Total CPU time spent executing process in user mode",
+    unit: Some("seconds"),
+    kind: Kind::Counter,
+};
+
+const CPU_KERNEL_TIME: Descriptor = Descriptor {
+    name: "cpu_kernel_time",
+    help: "Total CPU time spent executing process in kernel mode (e.g. in system calls)",
+    unit: Some("seconds"),
+    kind: Kind::Counter,
+};
+
+const ENERGY: Descriptor = Descriptor {
+    name: "energy",
+    help: "Total energy time spent executing process",
+    unit: Some("watt_hours"),
+    kind: Kind::Counter,
+};
+
+// Not available for the first collection of a process, as there is no previous sample to take
+// the interval from
+const POWER: Descriptor = Descriptor {
+    name: "power",
+    help: "Power attributed to process, averaged over the interval since the previous collection",
+    unit: Some("watts"),
+    kind: Kind::Gauge,
+};
+
+// Not available for the first collection of a process, as usage is calculated from the difference
+// in CPU time since the previous collection
+const CPU_USAGE: Descriptor = Descriptor {
+    name: "cpu_usage_ratio",
+    help: "CPU usage of process since the previous collection (1.0 is one fully utilised core)",
+    unit: None,
+    kind: Kind::Gauge,
+};
+
+const MEMORY_RESIDENT: Descriptor = Descriptor {
+    name: "memory_resident",
+    help: "Resident memory used by process",
+    unit: Some("bytes"),
+    kind: Kind::Gauge,
+};
+
+const MEMORY_VIRTUAL: Descriptor = Descriptor {
+    name: "memory_virtual",
+    help: "Virtual memory used by process",
+    unit: Some("bytes"),
+    kind: Kind::Gauge,
+};
+
+// Only available on Linux, and not for kernel threads
+const MEMORY_SWAP: Descriptor = Descriptor {
+    name: "swap",
+    help: "Swap used by process",
+    unit: Some("bytes"),
+    kind: Kind::Gauge,
+};
+
+const THREADS: Descriptor = Descriptor {
+    name: "threads",
+    help: "Number of threads in process",
+    unit: None,
+    kind: Kind::Gauge,
+};
+
+const OPEN_FILE_DESCRIPTORS: Descriptor = Descriptor {
+    name: "open_file_descriptors",
+    help: "Number of file descriptors open by process",
+    unit: None,
+    kind: Kind::Gauge,
+};
+
+const NICE: Descriptor = Descriptor {
+    name: "process_nice",
+    help: "Nice value of process (-20 is the highest priority, 19 the lowest)",
+    unit: None,
+    kind: Kind::Gauge,
+};
+
+const START_TIME: Descriptor = Descriptor {
+    name: "process_start_time",
+    help: "Start time of process since the Unix epoch",
+    unit: Some("seconds"),
+    kind: Kind::Gauge,
+};
+
+// Not available for processes owned by other users unless running as root, which report 0
+const DISK_READ: Descriptor = Descriptor {
+    name: "disk_read",
+    help: "Total bytes read from storage by process",
+    unit: Some("bytes"),
+    kind: Kind::Counter,
+};
+
+const DISK_WRITE: Descriptor = Descriptor {
+    name: "disk_write",
+    help: "Total bytes written to storage by process",
+    unit: Some("bytes"),
+    kind: Kind::Counter,
+};
+
+const VOLUNTARY_CONTEXT_SWITCHES: Descriptor = Descriptor {
+    name: "voluntary_context_switches",
+    help: "Total number of times process gave up the CPU (e.g. to wait for IO)",
+    unit: None,
+    kind: Kind::Counter,
+};
+
+const INVOLUNTARY_CONTEXT_SWITCHES: Descriptor = Descriptor {
+    name: "involuntary_context_switches",
+    help: "Total number of times process was preempted",
+    unit: None,
+    kind: Kind::Counter,
+};
+
+const MINOR_PAGE_FAULTS: Descriptor = Descriptor {
+    name: "minor_page_faults",
+    help: "Total number of page faults of process that did not require loading a page from disk",
+    unit: None,
+    kind: Kind::Counter,
+};
+
+const MAJOR_PAGE_FAULTS: Descriptor = Descriptor {
+    name: "major_page_faults",
+    help: "Total number of page faults of process that required loading a page from disk",
+    unit: None,
+    kind: Kind::Counter,
+};
+
+// Only present when the kernel has delay accounting enabled
+const BLOCK_IO_DELAY: Descriptor = Descriptor {
+    name: "block_io_delay",
+    help: "Total time process spent waiting for block IO (e.g. disk reads) to complete",
+    unit: Some("seconds"),
+    kind: Kind::Counter,
+};
+
+// Only present when GPU metrics are enabled, in the same unit as energy
+const GPU_ENERGY: Descriptor = Descriptor {
+    name: "gpu_energy",
+    help: "Total GPU energy attributed to process by its share of GPU utilisation",
+    unit: Some("watt_hours"),
+    kind: Kind::Counter,
+};
+
+const CPU_USAGE_DISTRIBUTION_NAME: &str = "cpu_usage_ratio_distribution";
+const CPU_USAGE_DISTRIBUTION_HELP: &str =
+    "Distribution of the CPU usage of process in each collection (1.0 is one fully utilised core)";
+
+/// Upper bounds of the CPU usage histogram buckets, usage above one core is only counted in the
+/// implicit +Inf bucket.
+const CPU_USAGE_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.25, 0.5, 0.75, 0.9, 1.0];
+
+fn cpu_usage_histogram() -> Histogram {
+    Histogram::new(CPU_USAGE_BUCKETS.into_iter())
+}
+
+type HistogramFamily = Family<Labels, Histogram, fn() -> Histogram>;
+
+const PROCESS_STATE_NAME: &str = "process_state";
+const PROCESS_STATE_HELP: &str =
+    "Number of processes in each state (e.g. running, sleeping, zombie), only states that any process is in are present";
+
+/// Labels of a series of the process state metric.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct StateLabels {
+    labels: Labels,
+    state: String,
+}
+
+impl EncodeLabelSet for StateLabels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), fmt::Error> {
+        let mut pairs = self.labels.pairs();
+        pairs.push(("state", &self.state));
+        labels::encode_pairs(pairs, encoder)
+    }
+}
+
+/// All per process metrics, in the same order as the values returned by [`Sample::values`].
+///
+/// Energy metrics are in watt hours here, see [`ProcessMetrics::descriptors`] for the exported
+/// units.
+const DESCRIPTORS: &[&Descriptor] = &[
+    &CPU_TIME,
+    &CPU_USER_TIME,
+    &CPU_KERNEL_TIME,
+    &ENERGY,
+    &POWER,
+    &CPU_USAGE,
+    &MEMORY_RESIDENT,
+    &MEMORY_VIRTUAL,
+    &MEMORY_SWAP,
+    &THREADS,
+    &OPEN_FILE_DESCRIPTORS,
+    &NICE,
+    &START_TIME,
+    &DISK_READ,
+    &DISK_WRITE,
+    &VOLUNTARY_CONTEXT_SWITCHES,
+    &INVOLUNTARY_CONTEXT_SWITCHES,
+    &MINOR_PAGE_FAULTS,
+    &MAJOR_PAGE_FAULTS,
+    &BLOCK_IO_DELAY,
+    &GPU_ENERGY,
+];
+
+/// Values of the per process metrics for a single label set.
+///
+/// When collected, counter values are the increase since the previous collection. When taken from
+/// [`ProcessMetrics::snapshot`], counter values are totals. Gauge values are always absolute.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Sample {
+    /// CPU time in seconds
+    pub(crate) cpu_time: f64,
+    /// CPU time in user mode in seconds, only available on Linux
+    pub(crate) cpu_user_time: Option<f64>,
+    /// CPU time in kernel mode in seconds, only available on Linux
+    pub(crate) cpu_kernel_time: Option<f64>,
+    /// Energy in the exported energy unit
+    pub(crate) energy: f64,
+    /// Power in Watts, not available if the process was not present at the previous collection
+    pub(crate) power: Option<f64>,
+    /// CPU usage since the previous collection, where 1.0 is one core fully utilised, not
+    /// available if the process was not present at the previous collection
+    pub(crate) cpu_usage: Option<f64>,
+    pub(crate) memory_resident: u64,
+    pub(crate) memory_virtual: u64,
+    /// Only available on Linux, not for kernel threads
+    pub(crate) memory_swap: Option<u64>,
+    /// Not available on all platforms
+    pub(crate) threads: Option<u64>,
+    /// Not available for processes that cannot be inspected (usually those owned by other users)
+    pub(crate) open_file_descriptors: Option<u64>,
+    /// Not available on all platforms, the lowest (i.e. highest priority) when combining processes
+    pub(crate) nice: Option<i64>,
+    /// Seconds since the Unix epoch, the earliest start time when combining processes
+    pub(crate) start_time: Option<u64>,
+    pub(crate) disk_read: u64,
+    pub(crate) disk_write: u64,
+    /// Not available on all platforms
+    pub(crate) voluntary_context_switches: Option<u64>,
+    /// Not available on all platforms
+    pub(crate) involuntary_context_switches: Option<u64>,
+    /// Not available on all platforms
+    pub(crate) minor_page_faults: Option<u64>,
+    /// Not available on all platforms
+    pub(crate) major_page_faults: Option<u64>,
+    /// Block IO delay in seconds, only available on Linux with delay accounting enabled
+    pub(crate) block_io_delay: Option<f64>,
+    /// GPU energy in the exported energy unit, only available when GPU metrics are enabled
+    pub(crate) gpu_energy: Option<f64>,
+    /// Number of processes in each state, only available on Linux
+    pub(crate) states: BTreeMap<String, u64>,
+}
+
+/// Sums two values that may not be available, the result is only `None` if neither is.
+fn add_optional<T: Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Sample {
+    /// Combines the values of two processes collected at the same time.
+    pub(crate) fn add(&mut self, other: &Sample) {
+        self.cpu_time += other.cpu_time;
+        self.cpu_user_time = add_optional(self.cpu_user_time, other.cpu_user_time);
+        self.cpu_kernel_time = add_optional(self.cpu_kernel_time, other.cpu_kernel_time);
+        self.energy += other.energy;
+        self.power = add_optional(self.power, other.power);
+        self.cpu_usage = add_optional(self.cpu_usage, other.cpu_usage);
+        self.memory_resident += other.memory_resident;
+        self.memory_virtual += other.memory_virtual;
+        self.memory_swap = add_optional(self.memory_swap, other.memory_swap);
+        self.threads = add_optional(self.threads, other.threads);
+        self.open_file_descriptors =
+            add_optional(self.open_file_descriptors, other.open_file_descriptors);
+        self.nice = match (self.nice, other.nice) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.start_time = match (self.start_time, other.start_time) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.disk_read += other.disk_read;
+        self.disk_write += other.disk_write;
+        self.voluntary_context_switches = add_optional(
+            self.voluntary_context_switches,
+            other.voluntary_context_switches,
+        );
+        self.involuntary_context_switches = add_optional(
+            self.involuntary_context_switches,
+            other.involuntary_context_switches,
+        );
+        self.minor_page_faults = add_optional(self.minor_page_faults, other.minor_page_faults);
+        self.major_page_faults = add_optional(self.major_page_faults, other.major_page_faults);
+        self.block_io_delay = add_optional(self.block_io_delay, other.block_io_delay);
+        self.gpu_energy = add_optional(self.gpu_energy, other.gpu_energy);
+        for (state, count) in &other.states {
+            *self.states.entry(state.clone()).or_default() += count;
+        }
+    }
+
+    /// Applies a newly collected sample to the totals from previous collections.
+    fn accumulate(&mut self, sample: &Sample) {
+        self.cpu_time += sample.cpu_time;
+        self.cpu_user_time = add_optional(self.cpu_user_time, sample.cpu_user_time);
+        self.cpu_kernel_time = add_optional(self.cpu_kernel_time, sample.cpu_kernel_time);
+        self.energy += sample.energy;
+        self.power = sample.power;
+        self.cpu_usage = sample.cpu_usage;
+        self.memory_resident = sample.memory_resident;
+        self.memory_virtual = sample.memory_virtual;
+        self.memory_swap = sample.memory_swap;
+        self.threads = sample.threads;
+        self.open_file_descriptors = sample.open_file_descriptors;
+        self.nice = sample.nice;
+        self.start_time = sample.start_time;
+        self.disk_read += sample.disk_read;
+        self.disk_write += sample.disk_write;
+        self.voluntary_context_switches = add_optional(
+            self.voluntary_context_switches,
+            sample.voluntary_context_switches,
+        );
+        self.involuntary_context_switches = add_optional(
+            self.involuntary_context_switches,
+            sample.involuntary_context_switches,
+        );
+        self.minor_page_faults = add_optional(self.minor_page_faults, sample.minor_page_faults);
+        self.major_page_faults = add_optional(self.major_page_faults, sample.major_page_faults);
+        self.block_io_delay = add_optional(self.block_io_delay, sample.block_io_delay);
+        self.gpu_energy = add_optional(self.gpu_energy, sample.gpu_energy);
+        self.states = sample.states.clone();
+    }
+
+    /// Values of each metric, in the same order as [`DESCRIPTORS`], `None` where a value is not
+    /// available.
+    pub(crate) fn values(&self) -> Vec<Option<f64>> {
+        vec![
+            Some(self.cpu_time),
+            self.cpu_user_time,
+            self.cpu_kernel_time,
+            Some(self.energy),
+            self.power,
+            self.cpu_usage,
+            Some(self.memory_resident as f64),
+            Some(self.memory_virtual as f64),
+            self.memory_swap.map(|v| v as f64),
+            self.threads.map(|v| v as f64),
+            self.open_file_descriptors.map(|v| v as f64),
+            self.nice.map(|v| v as f64),
+            self.start_time.map(|v| v as f64),
+            Some(self.disk_read as f64),
+            Some(self.disk_write as f64),
+            self.voluntary_context_switches.map(|v| v as f64),
+            self.involuntary_context_switches.map(|v| v as f64),
+            self.minor_page_faults.map(|v| v as f64),
+            self.major_page_faults.map(|v| v as f64),
+            self.block_io_delay,
+            self.gpu_energy,
+        ]
+    }
+}
+
+fn register(registry: &mut Registry, descriptor: &Descriptor, metric: impl Metric) {
+    match descriptor.unit {
+        Some(unit) => registry.register_with_unit(
+            descriptor.name,
+            descriptor.help,
+            Unit::Other(unit.to_string()),
+            metric,
+        ),
+        None => registry.register(descriptor.name, descriptor.help, metric),
+    }
+}
+
+/// Sets a gauge that is only present when its value is available.
+fn set_optional<N, A: Atomic<N> + Default>(
+    family: &Family<Labels, Gauge<N, A>>,
+    labels: &Labels,
+    value: Option<N>,
+) {
+    match value {
+        Some(value) => {
+            family.get_or_create(labels).set(value);
+        }
+        None => {
+            family.remove(labels);
+        }
+    }
+}
+
+/// Per process metric families.
+#[derive(Clone, Default)]
+pub(crate) struct ProcessMetrics {
+    energy_unit: EnergyUnit,
+    energy_metric: EnergyMetric,
+
+    cpu_time: Family<Labels, Counter<f64, AtomicU64>>,
+    cpu_user_time: Family<Labels, Counter<f64, AtomicU64>>,
+    cpu_kernel_time: Family<Labels, Counter<f64, AtomicU64>>,
+    energy: Family<Labels, Counter<f64, AtomicU64>>,
+    power: Family<Labels, Gauge<f64, AtomicU64>>,
+    cpu_usage: Family<Labels, Gauge<f64, AtomicU64>>,
+    memory_resident: Family<Labels, Gauge>,
+    memory_virtual: Family<Labels, Gauge>,
+    memory_swap: Family<Labels, Gauge>,
+    threads: Family<Labels, Gauge>,
+    open_file_descriptors: Family<Labels, Gauge>,
+    nice: Family<Labels, Gauge>,
+    start_time: Family<Labels, Gauge>,
+    disk_read: Family<Labels, Counter>,
+    disk_write: Family<Labels, Counter>,
+    voluntary_context_switches: Family<Labels, Counter>,
+    involuntary_context_switches: Family<Labels, Counter>,
+    minor_page_faults: Family<Labels, Counter>,
+    major_page_faults: Family<Labels, Counter>,
+    block_io_delay: Family<Labels, Counter<f64, AtomicU64>>,
+    gpu_energy: Family<Labels, Counter<f64, AtomicU64>>,
+    /// Only present if histograms are enabled, as each series holds a count for every bucket
+    cpu_usage_distribution: Option<HistogramFamily>,
+    state: Family<StateLabels, Gauge>,
+
+    /// Current values of every series, for exporters other than the Prometheus registry
+    totals: Arc<RwLock<HashMap<Labels, Sample>>>,
+}
+
+impl ProcessMetrics {
+    pub(crate) fn new(
+        enable_histograms: bool,
+        energy_unit: EnergyUnit,
+        energy_metric: EnergyMetric,
+    ) -> Self {
+        Self {
+            energy_unit,
+            energy_metric,
+            cpu_usage_distribution: enable_histograms
+                .then(|| Family::new_with_constructor(cpu_usage_histogram as fn() -> Histogram)),
+            ..Default::default()
+        }
+    }
+
+    /// The exported per process metrics with their exported units, each with the index of its
+    /// value in [`Sample::values`].
+    pub(crate) fn descriptors(&self) -> Vec<(usize, Descriptor)> {
+        DESCRIPTORS
+            .iter()
+            .enumerate()
+            .filter(|(_, descriptor)| match descriptor.name {
+                name if name == ENERGY.name => self.energy_metric.energy(),
+                name if name == POWER.name => self.energy_metric.power(),
+                _ => true,
+            })
+            .map(|(index, descriptor)| {
+                let descriptor = match descriptor.name {
+                    name if name == ENERGY.name || name == GPU_ENERGY.name => {
+                        descriptor.with_energy_unit(self.energy_unit)
+                    }
+                    _ => **descriptor,
+                };
+                (index, descriptor)
+            })
+            .collect()
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        register(registry, &CPU_TIME, self.cpu_time.clone());
+        register(registry, &CPU_USER_TIME, self.cpu_user_time.clone());
+        register(registry, &CPU_KERNEL_TIME, self.cpu_kernel_time.clone());
+        if self.energy_metric.energy() {
+            register(
+                registry,
+                &ENERGY.with_energy_unit(self.energy_unit),
+                self.energy.clone(),
+            );
+        }
+        if self.energy_metric.power() {
+            register(registry, &POWER, self.power.clone());
+        }
+        register(registry, &CPU_USAGE, self.cpu_usage.clone());
+        register(registry, &MEMORY_RESIDENT, self.memory_resident.clone());
+        register(registry, &MEMORY_VIRTUAL, self.memory_virtual.clone());
+        register(registry, &MEMORY_SWAP, self.memory_swap.clone());
+        register(registry, &THREADS, self.threads.clone());
+        register(
+            registry,
+            &OPEN_FILE_DESCRIPTORS,
+            self.open_file_descriptors.clone(),
+        );
+        register(registry, &NICE, self.nice.clone());
+        register(registry, &START_TIME, self.start_time.clone());
+        register(registry, &DISK_READ, self.disk_read.clone());
+        register(registry, &DISK_WRITE, self.disk_write.clone());
+        register(
+            registry,
+            &VOLUNTARY_CONTEXT_SWITCHES,
+            self.voluntary_context_switches.clone(),
+        );
+        register(
+            registry,
+            &INVOLUNTARY_CONTEXT_SWITCHES,
+            self.involuntary_context_switches.clone(),
+        );
+        register(registry, &MINOR_PAGE_FAULTS, self.minor_page_faults.clone());
+        register(registry, &MAJOR_PAGE_FAULTS, self.major_page_faults.clone());
+        register(registry, &BLOCK_IO_DELAY, self.block_io_delay.clone());
+        register(
+            registry,
+            &GPU_ENERGY.with_energy_unit(self.energy_unit),
+            self.gpu_energy.clone(),
+        );
+        if let Some(family) = &self.cpu_usage_distribution {
+            registry.register(
+                CPU_USAGE_DISTRIBUTION_NAME,
+                CPU_USAGE_DISTRIBUTION_HELP,
+                family.clone(),
+            );
+        }
+        registry.register(PROCESS_STATE_NAME, PROCESS_STATE_HELP, self.state.clone());
+    }
+
+    /// Removes the process state series of states that are not in `states`.
+    fn remove_states(&self, labels: &Labels, previous: &Sample, states: &BTreeMap<String, u64>) {
+        for state in previous.states.keys() {
+            if !states.contains_key(state) {
+                self.state.remove(&StateLabels {
+                    labels: labels.clone(),
+                    state: state.clone(),
+                });
+            }
+        }
+    }
+
+    pub(crate) fn update(&self, labels: &Labels, sample: &Sample) {
+        self.cpu_time.get_or_create(labels).inc_by(sample.cpu_time);
+        if let Some(v) = sample.cpu_user_time {
+            self.cpu_user_time.get_or_create(labels).inc_by(v);
+        }
+        if let Some(v) = sample.cpu_kernel_time {
+            self.cpu_kernel_time.get_or_create(labels).inc_by(v);
+        }
+        self.energy.get_or_create(labels).inc_by(sample.energy);
+        set_optional(&self.power, labels, sample.power);
+        set_optional(&self.cpu_usage, labels, sample.cpu_usage);
+        self.memory_resident
+            .get_or_create(labels)
+            .set(sample.memory_resident as i64);
+        self.memory_virtual
+            .get_or_create(labels)
+            .set(sample.memory_virtual as i64);
+        set_optional(
+            &self.memory_swap,
+            labels,
+            sample.memory_swap.map(|v| v as i64),
+        );
+        set_optional(&self.threads, labels, sample.threads.map(|v| v as i64));
+
+        set_optional(
+            &self.open_file_descriptors,
+            labels,
+            sample.open_file_descriptors.map(|v| v as i64),
+        );
+        set_optional(&self.nice, labels, sample.nice);
+        set_optional(
+            &self.start_time,
+            labels,
+            sample.start_time.map(|v| v as i64),
+        );
+        self.disk_read
+            .get_or_create(labels)
+            .inc_by(sample.disk_read);
+        self.disk_write
+            .get_or_create(labels)
+            .inc_by(sample.disk_write);
+        if let Some(v) = sample.voluntary_context_switches {
+            self.voluntary_context_switches
+                .get_or_create(labels)
+                .inc_by(v);
+        }
+        if let Some(v) = sample.involuntary_context_switches {
+            self.involuntary_context_switches
+                .get_or_create(labels)
+                .inc_by(v);
+        }
+        if let Some(v) = sample.minor_page_faults {
+            self.minor_page_faults.get_or_create(labels).inc_by(v);
+        }
+        if let Some(v) = sample.major_page_faults {
+            self.major_page_faults.get_or_create(labels).inc_by(v);
+        }
+        if let Some(v) = sample.block_io_delay {
+            self.block_io_delay.get_or_create(labels).inc_by(v);
+        }
+        if let Some(v) = sample.gpu_energy {
+            self.gpu_energy.get_or_create(labels).inc_by(v);
+        }
+        if let (Some(family), Some(cpu_usage)) = (&self.cpu_usage_distribution, sample.cpu_usage) {
+            family.get_or_create(labels).observe(cpu_usage);
+        }
+
+        for (state, count) in &sample.states {
+            self.state
+                .get_or_create(&StateLabels {
+                    labels: labels.clone(),
+                    state: state.clone(),
+                })
+                .set(*count as i64);
+        }
+
+        let mut totals = self.totals.write().unwrap();
+        let total = totals.entry(labels.clone()).or_default();
+        self.remove_states(labels, total, &sample.states);
+        total.accumulate(sample);
+    }
+
+    pub(crate) fn remove(&self, labels: &Labels) {
+        self.cpu_time.remove(labels);
+        self.cpu_user_time.remove(labels);
+        self.cpu_kernel_time.remove(labels);
+        self.energy.remove(labels);
+        self.power.remove(labels);
+        self.cpu_usage.remove(labels);
+        self.memory_resident.remove(labels);
+        self.memory_virtual.remove(labels);
+        self.memory_swap.remove(labels);
+        self.threads.remove(labels);
+        self.open_file_descriptors.remove(labels);
+        self.nice.remove(labels);
+        self.start_time.remove(labels);
+        self.disk_read.remove(labels);
+        self.disk_write.remove(labels);
+        self.voluntary_context_switches.remove(labels);
+        self.involuntary_context_switches.remove(labels);
+        self.minor_page_faults.remove(labels);
+        self.major_page_faults.remove(labels);
+        self.block_io_delay.remove(labels);
+        self.gpu_energy.remove(labels);
+        if let Some(family) = &self.cpu_usage_distribution {
+            family.remove(labels);
+        }
+
+        if let Some(total) = self.totals.write().unwrap().remove(labels) {
+            self.remove_states(labels, &total, &BTreeMap::new());
+        }
+    }
+
+    /// The current values of every series.
+    pub(crate) fn snapshot(&self) -> Vec<(Labels, Sample)> {
+        self.totals
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(labels, sample)| (labels.clone(), sample.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding;
+
+    fn labels(pid: &str) -> Labels {
+        Labels {
+            process_name: Some("test".to_string()),
+            pid: Some(pid.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn encode(metrics: &ProcessMetrics) -> String {
+        let mut registry = Registry::default();
+        metrics.register(&mut registry);
+        encoding::encode_openmetrics(&registry).unwrap()
+    }
+
+    #[test]
+    fn counters_keep_fractional_values() {
+        let metrics = ProcessMetrics::new(false, EnergyUnit::WattHours, EnergyMetric::Energy);
+        let sample = Sample {
+            cpu_time: 0.37,
+            energy: 0.0012,
+            ..Default::default()
+        };
+        metrics.update(&labels("1"), &sample);
+        metrics.update(&labels("1"), &sample);
+
+        let text = encode(&metrics);
+        assert!(text.contains("# TYPE cpu_time_seconds counter"), "{}", text);
+        assert!(
+            text.contains(r#"cpu_time_seconds_total{process_name="test",pid="1"} 0.74"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(r#"energy_watt_hours_total{process_name="test",pid="1"} 0.0024"#),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn remove_drops_series_of_exited_process() {
+        let metrics = ProcessMetrics::new(false, EnergyUnit::WattHours, EnergyMetric::Energy);
+        let sample = Sample {
+            cpu_time: 1.0,
+            memory_resident: 1024,
+            states: BTreeMap::from([("sleeping".to_string(), 1)]),
+            ..Default::default()
+        };
+        metrics.update(&labels("1"), &sample);
+        metrics.update(&labels("2"), &sample);
+
+        metrics.remove(&labels("1"));
+
+        let text = encode(&metrics);
+        assert!(!text.contains(r#"pid="1""#), "{}", text);
+        assert!(
+            text.contains(r#"cpu_time_seconds_total{process_name="test",pid="2"} 1.0"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(r#"process_state{process_name="test",pid="2",state="sleeping"} 1"#),
+            "{}",
+            text
+        );
+        assert_eq!(metrics.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn cpu_usage_only_exported_once_known() {
+        let metrics = ProcessMetrics::new(true, EnergyUnit::WattHours, EnergyMetric::Energy);
+        // The first sample of a process has no previous CPU time to compute usage from
+        let mut sample = Sample {
+            cpu_time: 1.0,
+            cpu_usage: None,
+            ..Default::default()
+        };
+        metrics.update(&labels("1"), &sample);
+
+        let text = encode(&metrics);
+        assert!(!text.contains("cpu_usage_ratio{"), "{}", text);
+        assert!(
+            !text.contains("cpu_usage_ratio_distribution_count{"),
+            "{}",
+            text
+        );
+
+        sample.cpu_usage = Some(0.25);
+        metrics.update(&labels("1"), &sample);
+
+        let text = encode(&metrics);
+        assert!(
+            text.contains(r#"cpu_usage_ratio{process_name="test",pid="1"} 0.25"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(r#"cpu_usage_ratio_distribution_count{process_name="test",pid="1"} 1"#),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn swap_only_exported_when_known() {
+        let metrics = ProcessMetrics::new(false, EnergyUnit::WattHours, EnergyMetric::Energy);
+        let sample = Sample {
+            memory_swap: Some(4096),
+            ..Default::default()
+        };
+        metrics.update(&labels("1"), &sample);
+        // e.g. kernel threads, which have no VmSwap
+        metrics.update(&labels("2"), &Sample::default());
+
+        let text = encode(&metrics);
+        assert!(text.contains("# UNIT swap_bytes bytes"), "{}", text);
+        assert!(
+            text.contains(r#"swap_bytes{process_name="test",pid="1"} 4096"#),
+            "{}",
+            text
+        );
+        assert!(
+            !text.contains(r#"swap_bytes{process_name="test",pid="2"}"#),
+            "{}",
+            text
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use crate::labels::{self, Labels};
+use anyhow::{bail, Result};
+use kagiyama::prometheus::{
+    encoding::{EncodeLabelSet, LabelSetEncoder},
+    metrics::{counter::Counter, family::Family},
+    registry::{Registry, Unit},
+};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+use sysinfo::Pid;
+
+/// Labels of a process series, plus the network namespace the counters are from.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct NetworkLabels {
+    labels: Labels,
+    netns: String,
+}
+
+impl EncodeLabelSet for NetworkLabels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        let mut pairs = self.labels.pairs();
+        pairs.push(("netns", &self.netns));
+        labels::encode_pairs(pairs, encoder)
+    }
+}
+
+/// Received and transmitted bytes of a network namespace.
+#[derive(Clone, Copy)]
+pub(crate) struct Counters {
+    pub(crate) rx_bytes: u64,
+    pub(crate) tx_bytes: u64,
+}
+
+/// Reads the network namespace (inode number) of a process.
+#[allow(unused_variables)]
+fn read_namespace(proc_path: &Path, pid: &Pid) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    return crate::procfs::get_network_namespace(proc_path, pid);
+
+    #[cfg(not(target_os = "linux"))]
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Reads the counters of the network namespace of a process.
+#[allow(unused_variables)]
+fn read_counters(proc_path: &Path, pid: &Pid) -> io::Result<Counters> {
+    #[cfg(target_os = "linux")]
+    return crate::procfs::get_network_counters(proc_path, pid);
+
+    #[cfg(not(target_os = "linux"))]
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Network traffic of the network namespace of each process.
+///
+/// The kernel only counts traffic per interface, so every process in a network namespace reports
+/// the traffic of the whole namespace (which for processes that are not in a container is usually
+/// all traffic of the host). Series are labelled with the namespace so that this is visible, and
+/// the traffic of a namespace is only counted once in each series.
+pub(crate) struct NetworkMetrics {
+    proc_path: PathBuf,
+
+    /// Counters of each series at the previous collection
+    previous: HashMap<NetworkLabels, Counters>,
+
+    rx: Family<NetworkLabels, Counter>,
+    tx: Family<NetworkLabels, Counter>,
+}
+
+impl NetworkMetrics {
+    pub(crate) fn new(proc_path: &Path) -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            bail!("Network metrics are only available on Linux");
+        }
+        Ok(Self {
+            proc_path: proc_path.to_path_buf(),
+            previous: HashMap::new(),
+            rx: Family::default(),
+            tx: Family::default(),
+        })
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "net_rx",
+            "Total bytes received on all interfaces (except loopback) of the network namespace of process",
+            Unit::Bytes,
+            self.rx.clone(),
+        );
+        registry.register_with_unit(
+            "net_tx",
+            "Total bytes transmitted on all interfaces (except loopback) of the network namespace of process",
+            Unit::Bytes,
+            self.tx.clone(),
+        );
+    }
+
+    /// Updates the counters of the series of the given processes, removing any series without
+    /// processes.
+    ///
+    /// Each namespace is read once per collection.
+    pub(crate) fn collect<'a>(
+        &mut self,
+        processes: impl IntoIterator<Item = (&'a Pid, &'a Labels)>,
+    ) {
+        let mut namespaces = HashMap::<u64, Counters>::new();
+        let mut current = HashMap::new();
+
+        for (pid, labels) in processes {
+            // The namespace of processes owned by other users cannot be read unless running as
+            // root, and processes may have exited since they were collected
+            let netns = match read_namespace(&self.proc_path, pid) {
+                Ok(netns) => netns,
+                Err(e) => {
+                    log::trace!(
+                        "Failed to read network namespace of PID {}, err: {}",
+                        pid,
+                        e
+                    );
+                    continue;
+                }
+            };
+            let counters = match namespaces.get(&netns) {
+                Some(counters) => *counters,
+                None => match read_counters(&self.proc_path, pid) {
+                    Ok(counters) => {
+                        namespaces.insert(netns, counters);
+                        counters
+                    }
+                    Err(e) => {
+                        log::trace!("Failed to read network of PID {}, err: {}", pid, e);
+                        continue;
+                    }
+                },
+            };
+
+            current.insert(
+                NetworkLabels {
+                    labels: labels.clone(),
+                    netns: netns.to_string(),
+                },
+                counters,
+            );
+        }
+
+        for (labels, counters) in &current {
+            // A new series counts all traffic of the namespace so far, in the same way as CPU time
+            let previous = self.previous.get(labels);
+            self.rx.get_or_create(labels).inc_by(
+                counters
+                    .rx_bytes
+                    .saturating_sub(previous.map_or(0, |p| p.rx_bytes)),
+            );
+            self.tx.get_or_create(labels).inc_by(
+                counters
+                    .tx_bytes
+                    .saturating_sub(previous.map_or(0, |p| p.tx_bytes)),
+            );
+        }
+
+        for labels in self.previous.keys() {
+            if !current.contains_key(labels) {
+                self.rx.remove(labels);
+                self.tx.remove(labels);
+            }
+        }
+        self.previous = current;
+    }
+}
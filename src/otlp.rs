@@ -0,0 +1,131 @@
+use crate::{
+    kubernetes::PodMetadata,
+    metrics::{Kind, ProcessMetrics},
+};
+use anyhow::{Context, Result};
+use opentelemetry::{
+    metrics::{AsyncInstrument, MeterProvider, ObservableCounter, ObservableGauge},
+    KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    Resource,
+};
+use std::time::Duration;
+
+/// Maps Prometheus style unit names to UCUM units, as used by OTLP.
+fn unit(unit: Option<&str>) -> &'static str {
+    match unit {
+        Some("seconds") => "s",
+        Some("bytes") => "By",
+        Some("joules") => "J",
+        Some("watts") => "W",
+        Some("watt_hours") => "W.h",
+        Some("kilowatt_hours") => "kW.h",
+        Some(_) | None => "",
+    }
+}
+
+/// Periodically pushes the per process metrics to an OTLP/HTTP collector.
+pub(crate) struct OtlpExporter {
+    provider: SdkMeterProvider,
+    _counters: Vec<ObservableCounter<f64>>,
+    _gauges: Vec<ObservableGauge<f64>>,
+}
+
+impl OtlpExporter {
+    pub(crate) fn new(
+        endpoint: &str,
+        interval: Duration,
+        hostname: &str,
+        pod: &PodMetadata,
+        labels: &[(String, String)],
+        metrics: &ProcessMetrics,
+    ) -> Result<Self> {
+        let exporter = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .context("Failed to create OTLP exporter")?;
+
+        let reader = PeriodicReader::builder(exporter)
+            .with_interval(interval)
+            .build();
+
+        let resource = Resource::builder()
+            .with_service_name("yuugi")
+            .with_attribute(KeyValue::new("host.name", hostname.to_string()))
+            .with_attributes(
+                pod.attributes()
+                    .into_iter()
+                    .map(|(name, value)| KeyValue::new(name, value.to_string())),
+            )
+            .with_attributes(
+                labels
+                    .iter()
+                    .map(|(name, value)| KeyValue::new(name.clone(), value.clone())),
+            )
+            .build();
+
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+
+        let meter = provider.meter("yuugi");
+
+        let mut counters = Vec::new();
+        let mut gauges = Vec::new();
+
+        for (index, descriptor) in metrics.descriptors() {
+            let metrics = metrics.clone();
+            let observe = move |observer: &dyn AsyncInstrument<f64>| {
+                for (labels, sample) in metrics.snapshot() {
+                    if let Some(value) = sample.values()[index] {
+                        let attributes: Vec<KeyValue> = labels
+                            .pairs()
+                            .into_iter()
+                            .map(|(k, v)| KeyValue::new(k.to_string(), v.to_string()))
+                            .collect();
+                        observer.observe(value, &attributes);
+                    }
+                }
+            };
+
+            match descriptor.kind {
+                Kind::Counter => counters.push(
+                    meter
+                        .f64_observable_counter(descriptor.name)
+                        .with_description(descriptor.help)
+                        .with_unit(unit(descriptor.unit))
+                        .with_callback(observe)
+                        .build(),
+                ),
+                Kind::Gauge => gauges.push(
+                    meter
+                        .f64_observable_gauge(descriptor.name)
+                        .with_description(descriptor.help)
+                        .with_unit(unit(descriptor.unit))
+                        .with_callback(observe)
+                        .build(),
+                ),
+            }
+        }
+
+        log::info!("Exporting metrics via OTLP to {}", endpoint);
+
+        Ok(Self {
+            provider,
+            _counters: counters,
+            _gauges: gauges,
+        })
+    }
+
+    /// Exports the final metric values and stops periodic exporting.
+    pub(crate) fn shutdown(self) -> Result<()> {
+        self.provider
+            .shutdown()
+            .context("Failed to shut down OTLP exporter")
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use sysinfo::{Pid, ProcessExt, System, SystemExt};
+
+/// Processes listed in PID files (as written by service managers and daemons) and all of their
+/// descendants.
+///
+/// The files are read every collection, so that a restarted service (with a new PID) is followed.
+pub(crate) struct PidFiles {
+    paths: Vec<PathBuf>,
+    /// The current problem with each file, so that each is only logged once
+    problems: HashMap<PathBuf, String>,
+}
+
+impl PidFiles {
+    pub(crate) fn new(paths: Vec<PathBuf>) -> Self {
+        Self {
+            paths,
+            problems: HashMap::new(),
+        }
+    }
+
+    /// Reads the PID in a file, checking that it is of a running process that started before the
+    /// file was written (otherwise the file is stale and the PID has been reused).
+    fn read(path: &Path, sys: &System) -> Result<Pid, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("cannot be read ({})", e))?;
+        let pid: Pid = contents
+            .trim()
+            .parse()
+            .map_err(|_| format!("does not contain a PID (\"{}\")", contents.trim()))?;
+
+        let process = sys
+            .process(pid)
+            .ok_or_else(|| format!("is stale, PID {} is not running", pid))?;
+
+        // Start times only have a resolution of a second
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        if let Some(modified) = modified {
+            if process.start_time() > modified.as_secs() + 1 {
+                return Err(format!(
+                    "is stale, PID {} has been reused by a process started after it was written",
+                    pid
+                ));
+            }
+        }
+
+        Ok(pid)
+    }
+
+    /// The processes in every file that could be read, and all of their descendants.
+    pub(crate) fn processes(&mut self, sys: &System) -> HashSet<Pid> {
+        let mut roots = Vec::new();
+        for path in &self.paths {
+            match Self::read(path, sys) {
+                Ok(pid) => {
+                    if self.problems.remove(path).is_some() {
+                        log::info!("PID file {} contains PID {}", path.display(), pid);
+                    }
+                    roots.push(pid);
+                }
+                Err(problem) => {
+                    if self.problems.get(path) != Some(&problem) {
+                        log::warn!("PID file {} {}", path.display(), problem);
+                        self.problems.insert(path.clone(), problem);
+                    }
+                }
+            }
+        }
+
+        let mut children = HashMap::<Pid, Vec<Pid>>::new();
+        for (pid, process) in sys.processes() {
+            if let Some(parent) = process.parent() {
+                children.entry(parent).or_default().push(*pid);
+            }
+        }
+
+        let mut processes = HashSet::new();
+        while let Some(pid) = roots.pop() {
+            if processes.insert(pid) {
+                roots.extend(children.get(&pid).into_iter().flatten());
+            }
+        }
+        processes
+    }
+}
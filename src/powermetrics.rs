@@ -0,0 +1,133 @@
+use crate::{
+    energy::{EnergyMeter, EnergyReading},
+    labels::HostLabels,
+};
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Parses the sample duration in seconds from the header of a sample, e.g.
+/// `*** Sampled system activity (Mon Jan  1 00:00:00 2024 +0000) (1004.12ms elapsed) ***`.
+fn parse_elapsed(line: &str) -> Option<f64> {
+    let (before, _) = line
+        .strip_prefix("*** Sampled")?
+        .rsplit_once("ms elapsed)")?;
+    let (_, elapsed) = before.rsplit_once('(')?;
+    elapsed.trim().parse::<f64>().ok().map(|ms| ms / 1000.0)
+}
+
+/// Parses the CPU power in Watts from a line of the `cpu_power` sampler, which is either
+/// `CPU Power: 1234 mW` (Apple silicon) or
+/// `Intel energy model derived package power (CPUs+GT+SA): 1.23W` (Intel).
+fn parse_power(line: &str) -> Option<f64> {
+    if let Some(power) = line.strip_prefix("CPU Power:") {
+        return power
+            .trim()
+            .strip_suffix("mW")?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|mw| mw / 1000.0);
+    }
+
+    let (_, power) = line
+        .strip_prefix("Intel energy model derived package power")?
+        .split_once(':')?;
+    power.trim().strip_suffix('W')?.trim().parse().ok()
+}
+
+/// CPU energy measurement on macOS via `powermetrics` (which must be run as root).
+///
+/// `powermetrics` runs for the lifetime of yuugi, sampling the average CPU power over each
+/// collection interval.
+pub(crate) struct Powermetrics {
+    child: Child,
+    energy: Arc<Mutex<f64>>,
+}
+
+impl Powermetrics {
+    /// Starts `powermetrics`, returning `None` if it is not available or does not produce a
+    /// sample (e.g. because yuugi is not running as root).
+    pub(crate) fn start(collection_interval: Duration) -> Option<Self> {
+        let mut child = match Command::new("powermetrics")
+            .args(["--samplers", "cpu_power", "--sample-rate"])
+            .arg(collection_interval.as_millis().max(1).to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                log::info!("powermetrics not available: {}", e);
+                return None;
+            }
+        };
+
+        let stdout = child.stdout.take()?;
+        let energy = Arc::new(Mutex::new(0.0));
+        let (first_sample_tx, first_sample_rx) = mpsc::channel();
+
+        {
+            let energy = energy.clone();
+            thread::spawn(move || {
+                let mut elapsed = None;
+
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    if let Some(e) = parse_elapsed(&line) {
+                        elapsed = Some(e);
+                    } else if let (Some(power), Some(elapsed)) = (parse_power(&line), elapsed) {
+                        *energy.lock().unwrap() += power * elapsed;
+                        first_sample_tx.send(()).ok();
+                    }
+                }
+
+                log::warn!("powermetrics exited, no further energy will be measured");
+            });
+        }
+
+        match first_sample_rx.recv_timeout(collection_interval * 2 + Duration::from_secs(2)) {
+            Ok(()) => {
+                log::info!("Using powermetrics for CPU energy");
+                *energy.lock().unwrap() = 0.0;
+                Some(Self { child, energy })
+            }
+            Err(_) => {
+                log::info!("powermetrics produced no samples (it must be run as root)");
+                child.kill().ok();
+                None
+            }
+        }
+    }
+}
+
+impl EnergyMeter for Powermetrics {
+    fn name(&self) -> &'static str {
+        "powermetrics"
+    }
+
+    fn description(&self) -> &'static str {
+        "Total energy consumed by the CPU as measured by powermetrics"
+    }
+
+    fn read_energy(&mut self) -> EnergyReading {
+        let energy = std::mem::take(&mut *self.energy.lock().unwrap());
+        EnergyReading {
+            cpu: energy,
+            domains: vec![(
+                HostLabels(vec![("domain".to_string(), "cpu".to_string())]),
+                energy,
+            )],
+            sockets: Vec::new(),
+        }
+    }
+}
+
+impl Drop for Powermetrics {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+    }
+}
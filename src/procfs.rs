@@ -0,0 +1,949 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use sysinfo::Pid;
+
+// CPU time is read from /proc/[pid]/stat on Linux, other platforms without /proc use sysinfo
+#[cfg(target_os = "linux")]
+use {
+    crate::{
+        network::Counters,
+        snapshot::{ProcessSnapshot, SnapshotSource, ThreadSnapshot},
+    },
+    anyhow::{anyhow, Result},
+    std::{
+        collections::HashSet,
+        str::FromStr,
+        time::{Duration, Instant},
+    },
+    sysinfo::Process,
+};
+
+/// Path of a file of a process in the proc filesystem (e.g. `/proc/[pid]/stat`).
+fn process_file(proc_path: &Path, pid: &Pid, file: &str) -> PathBuf {
+    proc_path.join(pid.to_string()).join(file)
+}
+
+#[cfg(target_os = "linux")]
+/// Fields of `/proc/[pid]/stat` used for metrics, named as in proc(5).
+///
+/// Times are in clock ticks (jiffies).
+struct ProcStat {
+    state: char,
+    minflt: u64,
+    majflt: u64,
+    utime: u64,
+    stime: u64,
+    nice: i64,
+    num_threads: u64,
+    /// Added in Linux 2.2.8
+    processor: Option<u64>,
+    /// Added in Linux 2.5.19
+    policy: Option<u64>,
+    /// Added in Linux 2.6.18, always 0 if delay accounting is disabled
+    delayacct_blkio_ticks: Option<u64>,
+}
+
+#[cfg(target_os = "linux")]
+/// Parses the numbered (starting at 1, as in proc(5)) field from the fields following `comm`.
+fn parse_stat_field<T: FromStr>(fields: &[&str], number: usize) -> Option<T> {
+    fields.get(number - 3)?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+/// Parses a field that is present in all supported kernels.
+fn required_stat_field<T: FromStr>(fields: &[&str], name: &str, number: usize) -> Result<T> {
+    parse_stat_field(fields, number)
+        .ok_or_else(|| anyhow!("missing or invalid field {} ({})", name, number))
+}
+
+#[cfg(target_os = "linux")]
+impl ProcStat {
+    /// Parses the contents of `/proc/[pid]/stat`.
+    ///
+    /// The `comm` field (2) is wrapped in parentheses but may itself contain spaces and
+    /// parentheses, so the fields following it are located from the last `)` in the file.
+    fn parse(contents: &str) -> Result<Self> {
+        let (_, remainder) = contents
+            .rsplit_once(')')
+            .ok_or_else(|| anyhow!("no comm field"))?;
+        // Starting at field 3 (state)
+        let fields: Vec<&str> = remainder.split_whitespace().collect();
+
+        Ok(Self {
+            state: required_stat_field(&fields, "state", 3)?,
+            minflt: required_stat_field(&fields, "minflt", 10)?,
+            majflt: required_stat_field(&fields, "majflt", 12)?,
+            utime: required_stat_field(&fields, "utime", 14)?,
+            stime: required_stat_field(&fields, "stime", 15)?,
+            nice: required_stat_field(&fields, "nice", 19)?,
+            num_threads: required_stat_field(&fields, "num_threads", 20)?,
+            processor: parse_stat_field(&fields, 39),
+            policy: parse_stat_field(&fields, 41),
+            delayacct_blkio_ticks: parse_stat_field(&fields, 42),
+        })
+    }
+
+    /// Total CPU time in user and kernel mode.
+    fn jiffies(&self) -> u64 {
+        self.utime + self.stime
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Name of a process state (see proc(5)), the state character if it is not known.
+fn state_name(state: char) -> String {
+    match state {
+        'R' => "running".to_string(),
+        'S' => "sleeping".to_string(),
+        'D' => "disk_sleep".to_string(),
+        'Z' => "zombie".to_string(),
+        'T' => "stopped".to_string(),
+        't' => "tracing_stop".to_string(),
+        'X' | 'x' => "dead".to_string(),
+        'K' => "wakekill".to_string(),
+        'W' => "waking".to_string(),
+        'P' => "parked".to_string(),
+        'I' => "idle".to_string(),
+        state => state.to_string(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Name of a scheduling policy (see sched(7)), the number if it is not known.
+fn sched_policy_name(policy: u64) -> String {
+    match policy {
+        0 => "other".to_string(),
+        1 => "fifo".to_string(),
+        2 => "rr".to_string(),
+        3 => "batch".to_string(),
+        5 => "idle".to_string(),
+        6 => "deadline".to_string(),
+        policy => policy.to_string(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Reads `/proc/[pid]/stat`, `Ok(None)` if it could not be parsed.
+fn get_process_stat(proc_path: &Path, pid: &Pid) -> io::Result<Option<ProcStat>> {
+    match ProcStat::parse(&fs::read_to_string(process_file(proc_path, pid, "stat"))?) {
+        Ok(stat) => {
+            log::trace!(
+                "PID {}: user={} kernel={} threads={}",
+                pid,
+                stat.utime,
+                stat.stime,
+                stat.num_threads
+            );
+            Ok(Some(stat))
+        }
+        Err(e) => {
+            log::warn!("Failed to parse process stat PID={}, err: {}", pid, e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Reads the CPU time of every thread of a process from `/proc/[pid]/task/[tid]/stat`.
+///
+/// Threads that exit while the threads are being read are skipped.
+fn get_threads(
+    proc_path: &Path,
+    pid: &Pid,
+    jiffy_in_seconds: f64,
+) -> io::Result<Vec<ThreadSnapshot>> {
+    let mut threads = Vec::new();
+
+    for entry in fs::read_dir(process_file(proc_path, pid, "task"))? {
+        let entry = entry?;
+        let tid: u64 = match entry.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+
+        let contents = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::trace!(
+                    "Thread {} of PID {} exited before it could be read",
+                    tid,
+                    pid
+                );
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let stat = match ProcStat::parse(&contents) {
+            Ok(stat) => stat,
+            Err(e) => {
+                log::warn!(
+                    "Failed to parse thread stat PID={} TID={}, err: {}",
+                    pid,
+                    tid,
+                    e
+                );
+                continue;
+            }
+        };
+        // Between the first "(" and the last ")", see ProcStat::parse
+        let name = contents
+            .split_once('(')
+            .and_then(|(_, rest)| rest.rsplit_once(')'))
+            .map_or("", |(name, _)| name);
+
+        threads.push(ThreadSnapshot {
+            tid,
+            name: name.to_string(),
+            cpu_time: (stat.jiffies() as f64) * jiffy_in_seconds,
+        });
+    }
+
+    Ok(threads)
+}
+
+#[cfg(target_os = "linux")]
+/// Fields of `/proc/[pid]/status` used for metrics.
+struct Status {
+    voluntary_context_switches: u64,
+    involuntary_context_switches: u64,
+    /// Bytes of swap used, not present for kernel threads
+    swap: Option<u64>,
+}
+
+#[cfg(target_os = "linux")]
+impl Status {
+    fn parse(contents: &str) -> Option<Self> {
+        let field = |name: &str| {
+            contents
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| *key == name)
+                .and_then(|(_, value)| value.trim().parse().ok())
+        };
+
+        // Memory sizes are in kB (actually KiB)
+        let kilobytes = |name: &str| {
+            contents
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| *key == name)
+                .and_then(|(_, value)| value.trim().strip_suffix(" kB")?.parse::<u64>().ok())
+                .map(|kb| kb * 1024)
+        };
+
+        Some(Self {
+            voluntary_context_switches: field("voluntary_ctxt_switches")?,
+            involuntary_context_switches: field("nonvoluntary_ctxt_switches")?,
+            swap: kilobytes("VmSwap"),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Reads `/proc/[pid]/status`, `Ok(None)` if it could not be parsed.
+fn get_process_status(proc_path: &Path, pid: &Pid) -> io::Result<Option<Status>> {
+    let status = Status::parse(&fs::read_to_string(process_file(proc_path, pid, "status"))?);
+    if status.is_none() {
+        log::warn!("Failed to parse process status PID={}", pid);
+    }
+    Ok(status)
+}
+
+#[cfg(target_os = "linux")]
+/// Checks if delay accounting is enabled.
+///
+/// Since Linux 5.14 it is disabled by default (and controlled by the `kernel.task_delayacct`
+/// sysctl), before which it is enabled in kernels that support it.
+fn delay_accounting_enabled(proc_path: &Path) -> bool {
+    match fs::read_to_string(proc_path.join("sys/kernel/task_delayacct")) {
+        Ok(value) if value.trim() == "0" => {
+            log::info!("Delay accounting is disabled, so block IO delays are not available (enable with sysctl kernel.task_delayacct=1)");
+            false
+        }
+        _ => true,
+    }
+}
+
+#[cfg(target_os = "linux")]
+const FALLBACK_CLOCK_TICKS_PER_SECOND: u64 = 100;
+
+#[cfg(target_os = "linux")]
+/// Reads the total clock ticks (jiffies) of all online CPUs in every state (including idle and
+/// steal) from `/proc/stat`, with the number of CPUs.
+fn get_total_cpu_ticks(proc_path: &Path) -> io::Result<(u64, usize)> {
+    let contents = fs::read_to_string(proc_path.join("stat"))?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unexpected /proc/stat format");
+
+    // The first line is the total of all CPUs, followed by a line per CPU. Of the states, guest
+    // time is already counted in user time, so only the first 8 are summed.
+    let total = contents
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("cpu "))
+        .ok_or_else(invalid)?;
+    let ticks = total
+        .split_whitespace()
+        .take(8)
+        .map(|field| field.parse::<u64>().map_err(|_| invalid()))
+        .sum::<io::Result<u64>>()?;
+    let cpus = contents
+        .lines()
+        .filter(|line| {
+            line.strip_prefix("cpu")
+                .is_some_and(|cpu| cpu.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .count();
+
+    Ok((ticks, cpus))
+}
+
+#[cfg(target_os = "linux")]
+/// How long the clock tick rate is measured for before it is compared to the assumed rate.
+const CLOCK_TICK_CHECK_DURATION: Duration = Duration::from_secs(10);
+
+#[cfg(target_os = "linux")]
+/// Checks that the number of clock ticks per second matches the rate at which the kernel counts
+/// CPU time, which can differ in some virtualised environments and would scale all CPU time.
+///
+/// Every CPU accumulates one tick per tick period in some state, so over wall time the total
+/// ticks of all CPUs in `/proc/stat` increase at the clock tick rate times the number of CPUs.
+struct ClockTickCheck {
+    proc_path: PathBuf,
+    started: Instant,
+    ticks: u64,
+    cpus: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl ClockTickCheck {
+    fn start(proc_path: &Path) -> Option<Self> {
+        match get_total_cpu_ticks(proc_path) {
+            Ok((ticks, cpus)) => Some(Self {
+                proc_path: proc_path.to_path_buf(),
+                started: Instant::now(),
+                ticks,
+                cpus,
+            }),
+            Err(e) => {
+                log::debug!("Cannot check clock ticks per second, err: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Measures the clock tick rate once enough time has passed, returning `true` when the check
+    /// is complete.
+    fn check(&mut self, clock_ticks_per_second: f64) -> bool {
+        let elapsed = self.started.elapsed();
+        if elapsed < CLOCK_TICK_CHECK_DURATION {
+            return false;
+        }
+
+        let (ticks, cpus) = match get_total_cpu_ticks(&self.proc_path) {
+            Ok(reading) => reading,
+            Err(e) => {
+                log::debug!("Cannot check clock ticks per second, err: {}", e);
+                return true;
+            }
+        };
+        // CPUs coming online or going offline change the rate, so start again
+        if cpus != self.cpus || cpus == 0 {
+            self.started = Instant::now();
+            self.ticks = ticks;
+            self.cpus = cpus;
+            return false;
+        }
+
+        let measured =
+            (ticks.saturating_sub(self.ticks) as f64) / elapsed.as_secs_f64() / (cpus as f64);
+        if (measured / clock_ticks_per_second - 1.0).abs() > 0.2 {
+            log::warn!(
+                "CPU time is counted at {:.0} clock ticks per second, but {} is assumed, so CPU time (and energy) is likely to be scaled wrongly (set --clock-ticks-per-second {:.0} if this is consistent)",
+                measured,
+                clock_ticks_per_second,
+                measured
+            );
+        } else {
+            log::debug!(
+                "CPU time is counted at {:.0} clock ticks per second, as assumed",
+                measured
+            );
+        }
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Process snapshots from sysinfo and `/proc/[pid]/{stat,status,fd}`, each of which is read once
+/// per collection.
+pub(crate) struct ProcSnapshotSource {
+    proc_path: PathBuf,
+    jiffy_in_seconds: f64,
+    /// Where the number of clock ticks per second came from ("sysconf", "override" or "fallback")
+    clock_ticks_source: &'static str,
+    /// Whether the kernel records block IO delays, without which they are always 0
+    delay_accounting: bool,
+    /// Whether to read the CPU time of each thread
+    per_thread: bool,
+    /// Until it is complete
+    clock_tick_check: Option<ClockTickCheck>,
+    /// Processes for which a read has been denied, so that this is only logged once per process
+    permission_denied: HashSet<Pid>,
+    /// Processes collected in the current collection
+    collected: HashSet<Pid>,
+    /// Failures to read or parse a file since errors were last taken
+    errors: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcSnapshotSource {
+    /// Uses the given number of clock ticks (jiffies) per second if set, otherwise the value from
+    /// sysconf, or 100 (which is almost always the value on Linux) if that is not available.
+    pub(crate) fn new(
+        proc_path: &Path,
+        clock_ticks_per_second: Option<u64>,
+        per_thread: bool,
+    ) -> Self {
+        let (clk_tck, clock_ticks_source) = match clock_ticks_per_second {
+            Some(clk_tck) => (clk_tck, "override"),
+            None => match sysconf::raw::sysconf(sysconf::raw::SysconfVariable::ScClkTck) {
+                Ok(clk_tck) if clk_tck > 0 => (clk_tck as u64, "sysconf"),
+                result => {
+                    log::warn!(
+                        "Failed to get clock ticks per second from sysconf ({:?}), assuming {} (set --clock-ticks-per-second if this is wrong)",
+                        result,
+                        FALLBACK_CLOCK_TICKS_PER_SECOND
+                    );
+                    (FALLBACK_CLOCK_TICKS_PER_SECOND, "fallback")
+                }
+            },
+        };
+        let jiffy_in_seconds = 1.0 / (clk_tck as f64);
+        log::info!(
+            "1 jiffy is {} seconds (from {})",
+            jiffy_in_seconds,
+            clock_ticks_source
+        );
+
+        Self {
+            proc_path: proc_path.to_path_buf(),
+            jiffy_in_seconds,
+            clock_ticks_source,
+            delay_accounting: delay_accounting_enabled(proc_path),
+            per_thread,
+            clock_tick_check: ClockTickCheck::start(proc_path),
+            permission_denied: HashSet::new(),
+            collected: HashSet::new(),
+            errors: 0,
+        }
+    }
+
+    /// Handles a failure to read a file in `/proc/[pid]`.
+    ///
+    /// A process that does not exist has exited since the process list was refreshed, and reads of
+    /// some files of processes owned by other users are denied unless running as root, neither of
+    /// which are worth warning about.
+    fn read_failed(&mut self, pid: &Pid, file: &str, e: io::Error) {
+        match e.kind() {
+            io::ErrorKind::NotFound => {
+                log::trace!("PID {} exited before {} could be read", pid, file);
+            }
+            io::ErrorKind::PermissionDenied => {
+                if self.permission_denied.insert(*pid) {
+                    log::debug!("Permission denied reading {} of PID {}", file, pid);
+                }
+            }
+            _ => {
+                log::warn!("Failed to read process {} PID={}, err: {}", file, pid, e);
+                self.errors += 1;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl SnapshotSource for ProcSnapshotSource {
+    fn collect(&mut self, pid: &Pid, process: &Process) -> Option<ProcessSnapshot> {
+        self.collected.insert(*pid);
+
+        // Without CPU time there is nothing useful to export, so the process is skipped
+        let stat = match get_process_stat(&self.proc_path, pid) {
+            Ok(Some(stat)) => stat,
+            Ok(None) => {
+                self.errors += 1;
+                return None;
+            }
+            Err(e) => {
+                self.read_failed(pid, "stat", e);
+                return None;
+            }
+        };
+        let status = match get_process_status(&self.proc_path, pid) {
+            Ok(Some(status)) => Some(status),
+            Ok(None) => {
+                self.errors += 1;
+                None
+            }
+            Err(e) => {
+                self.read_failed(pid, "status", e);
+                None
+            }
+        };
+        let open_file_descriptors = get_open_file_descriptors(&self.proc_path, pid).map_or_else(
+            |e| {
+                self.read_failed(pid, "fd", e);
+                None
+            },
+            Some,
+        );
+
+        let mut snapshot =
+            ProcessSnapshot::new(process, (stat.jiffies() as f64) * self.jiffy_in_seconds);
+        snapshot.cpu_user_time = Some((stat.utime as f64) * self.jiffy_in_seconds);
+        snapshot.cpu_kernel_time = Some((stat.stime as f64) * self.jiffy_in_seconds);
+        snapshot.num_threads = Some(stat.num_threads);
+        snapshot.last_cpu = stat.processor.map(|cpu| cpu as usize);
+        snapshot.nice = Some(stat.nice);
+        snapshot.sched_policy = stat.policy.map(sched_policy_name);
+        snapshot.state = Some(state_name(stat.state));
+        snapshot.open_file_descriptors = open_file_descriptors;
+        snapshot.memory_swap = status.as_ref().and_then(|s| s.swap);
+        snapshot.voluntary_context_switches = status.as_ref().map(|s| s.voluntary_context_switches);
+        snapshot.involuntary_context_switches = status.map(|s| s.involuntary_context_switches);
+        snapshot.minor_page_faults = Some(stat.minflt);
+        snapshot.major_page_faults = Some(stat.majflt);
+        if self.delay_accounting {
+            snapshot.block_io_delay = stat
+                .delayacct_blkio_ticks
+                .map(|ticks| (ticks as f64) * self.jiffy_in_seconds);
+        }
+        if self.per_thread {
+            match get_threads(&self.proc_path, pid, self.jiffy_in_seconds) {
+                Ok(threads) => snapshot.threads = threads,
+                Err(e) => self.read_failed(pid, "task", e),
+            }
+        }
+
+        Some(snapshot)
+    }
+
+    fn end_collection(&mut self) {
+        let collected = std::mem::take(&mut self.collected);
+        self.permission_denied.retain(|pid| collected.contains(pid));
+
+        let clock_ticks_per_second = 1.0 / self.jiffy_in_seconds;
+        if let Some(check) = &mut self.clock_tick_check {
+            if check.check(clock_ticks_per_second) {
+                self.clock_tick_check = None;
+            }
+        }
+    }
+
+    fn take_errors(&mut self) -> u64 {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn info(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "jiffy_in_seconds".to_string(),
+                self.jiffy_in_seconds.to_string(),
+            ),
+            (
+                "clock_ticks_source".to_string(),
+                self.clock_ticks_source.to_string(),
+            ),
+        ]
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_open_file_descriptors(proc_path: &Path, pid: &Pid) -> io::Result<u64> {
+    Ok(fs::read_dir(process_file(proc_path, pid, "fd"))?.count() as u64)
+}
+
+#[cfg(target_os = "linux")]
+/// Reads the inode number of the network namespace of a process from `/proc/[pid]/ns/net`
+/// (e.g. `net:[4026531840]`).
+pub(crate) fn get_network_namespace(proc_path: &Path, pid: &Pid) -> io::Result<u64> {
+    let link = fs::read_link(process_file(proc_path, pid, "ns/net"))?;
+    link.to_str()
+        .and_then(|link| link.strip_prefix("net:["))
+        .and_then(|link| link.strip_suffix(']'))
+        .and_then(|inode| inode.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected namespace {}", link.display()),
+            )
+        })
+}
+
+#[cfg(target_os = "linux")]
+/// Reads the total bytes received and transmitted on all interfaces except loopback of the
+/// network namespace of a process from `/proc/[pid]/net/dev`.
+pub(crate) fn get_network_counters(proc_path: &Path, pid: &Pid) -> io::Result<Counters> {
+    let contents = fs::read_to_string(process_file(proc_path, pid, "net/dev"))?;
+
+    let mut counters = Counters {
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    // After two header lines, each line is an interface name followed by 8 receive and 8
+    // transmit fields, the first of each being bytes
+    for line in contents.lines().skip(2) {
+        let (interface, fields) = match line.split_once(':') {
+            Some(line) => line,
+            None => continue,
+        };
+        if interface.trim() == "lo" {
+            continue;
+        }
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let field = |index: usize| fields.get(index).and_then(|v| v.parse::<u64>().ok());
+        counters.rx_bytes += field(0).unwrap_or(0);
+        counters.tx_bytes += field(8).unwrap_or(0);
+    }
+    Ok(counters)
+}
+
+/// Cgroup membership of a process, from `/proc/[pid]/cgroup`.
+pub(crate) struct Cgroup {
+    /// Path relative to the cgroup mount, `/` for processes in the root cgroup
+    pub(crate) path: String,
+    /// Container ID, if the path looks like one created by a container runtime
+    pub(crate) container_id: Option<String>,
+    /// Kubernetes pod UID, if the path looks like one created by the kubelet
+    pub(crate) pod_uid: Option<String>,
+    /// systemd unit, if the path looks like one created by systemd
+    pub(crate) systemd_unit: Option<String>,
+}
+
+impl Cgroup {
+    fn parse(contents: &str) -> Option<Self> {
+        // Each line is "hierarchy-ID:controller-list:path".
+        // With cgroup v2 there is a single line with ID 0 and no controllers, with cgroup v1 (or a
+        // hybrid hierarchy) the hierarchy with the CPU controller is used as that is what
+        // container runtimes use for CPU accounting.
+        let hierarchies: Vec<(&str, &str, &str)> = contents
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, ':');
+                Some((parts.next()?, parts.next()?, parts.next()?))
+            })
+            .collect();
+
+        let (_, _, path) = hierarchies
+            .iter()
+            .find(|(_, controllers, _)| controllers.split(',').any(|c| c == "cpu"))
+            .or_else(|| {
+                hierarchies
+                    .iter()
+                    .find(|(id, controllers, _)| *id == "0" && controllers.is_empty())
+            })
+            .or_else(|| hierarchies.first())?;
+
+        Some(Self {
+            path: path.to_string(),
+            container_id: path.split('/').rev().find_map(parse_container_id),
+            pod_uid: path.split('/').rev().find_map(parse_pod_uid),
+            systemd_unit: parse_systemd_unit(path),
+        })
+    }
+}
+
+/// Extracts a container ID from a cgroup path component.
+///
+/// Handles both the cgroupfs driver (e.g. `/docker/<id>`) and the systemd driver (e.g.
+/// `docker-<id>.scope`, `cri-containerd-<id>.scope`, `crio-<id>.scope`).
+fn parse_container_id(component: &str) -> Option<String> {
+    let component = component.strip_suffix(".scope").unwrap_or(component);
+    let id = component.rsplit('-').next()?;
+
+    match id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(id.to_string()),
+        false => None,
+    }
+}
+
+/// Extracts a Kubernetes pod UID from a cgroup path component.
+///
+/// Handles both the cgroupfs driver (e.g. `/kubepods/burstable/pod<uid>`) and the systemd driver
+/// (e.g. `kubepods-burstable-pod<uid>.slice`, where the dashes in the UID are replaced with
+/// underscores).
+fn parse_pod_uid(component: &str) -> Option<String> {
+    let component = component.strip_suffix(".slice").unwrap_or(component);
+    let (_, uid) = component.rsplit_once("pod")?;
+    let uid = uid.replace('_', "-");
+
+    match uid.len() == 36 && uid.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        true => Some(uid),
+        false => None,
+    }
+}
+
+/// Extracts the systemd unit managing a process from its cgroup path.
+///
+/// This is the innermost service or scope (e.g. `nginx.service` from `/system.slice/nginx.service`,
+/// or `app.service` from
+/// `/user.slice/user-1000.slice/user@1000.service/app.slice/app.service` for a unit of a user
+/// session), otherwise the innermost slice.
+fn parse_systemd_unit(path: &str) -> Option<String> {
+    let components: Vec<&str> = path.split('/').collect();
+
+    components
+        .iter()
+        .rev()
+        .find(|c| c.ends_with(".service") || c.ends_with(".scope"))
+        .or_else(|| components.iter().rev().find(|c| c.ends_with(".slice")))
+        .map(|c| c.to_string())
+}
+
+/// Reads the environment a process was started with from `/proc/[pid]/environ`, which is
+/// usually only readable by the owner of the process and root.
+///
+/// Variables are separated by NUL bytes. Values that are not UTF-8 are converted lossily, and
+/// only the first definition of a variable is kept (as with getenv).
+pub(crate) fn get_environment(proc_path: &Path, pid: &Pid) -> io::Result<HashMap<String, String>> {
+    let contents = fs::read(process_file(proc_path, pid, "environ"))?;
+
+    let mut variables = HashMap::new();
+    for variable in contents.split(|b| *b == 0) {
+        let variable = String::from_utf8_lossy(variable);
+        if let Some((name, value)) = variable.split_once('=') {
+            variables
+                .entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    Ok(variables)
+}
+
+pub(crate) fn get_cgroup(proc_path: &Path, pid: &Pid) -> Option<Cgroup> {
+    match fs::read_to_string(process_file(proc_path, pid, "cgroup")) {
+        Ok(contents) => {
+            let cgroup = Cgroup::parse(&contents);
+            if cgroup.is_none() {
+                log::warn!("Failed to parse process cgroup PID={}", pid);
+            }
+            cgroup
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::trace!("PID {} exited before cgroup could be read", pid);
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to get process cgroup PID={}, err: {}", pid, e);
+            None
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use sysinfo::{System, SystemExt};
+
+    /// Fields 3 (state) onwards of `/proc/[pid]/stat`, with distinct values for the fields that
+    /// are used.
+    const STAT_FIELDS: &str = "S 1 1234 1234 0 -1 4194560 1001 2002 3003 4004 505 606 7 8 25 5 9 0 727677 2703360 306 18446744073709551615 0 0 0 0 0 0 0 0 0 0 0 0 17 3 0 3 11 0 0 0 0 0 0 0 0 0 0";
+
+    fn stat_line(comm: &str) -> String {
+        format!("1234 ({}) {}\n", comm, STAT_FIELDS)
+    }
+
+    /// A temporary directory standing in for the proc filesystem, removed when dropped.
+    struct FakeProc(PathBuf);
+
+    impl FakeProc {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("yuugi-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&path).ok();
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// Creates a file of a process, and any directories it is in.
+        fn write(&self, pid: &Pid, file: &str, contents: &str) {
+            let path = process_file(&self.0, pid, file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn open_file_descriptors_are_counted() {
+        let proc = FakeProc::new("fd");
+        let pid = Pid::from(42);
+        for fd in ["0", "1", "2"] {
+            proc.write(&pid, &format!("fd/{}", fd), "");
+        }
+
+        assert_eq!(get_open_file_descriptors(&proc.0, &pid).unwrap(), 3);
+    }
+
+    #[test]
+    fn open_file_descriptors_of_exited_process() {
+        let proc = FakeProc::new("fd-exited");
+        let e = get_open_file_descriptors(&proc.0, &Pid::from(42)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        let mut source = ProcSnapshotSource::new(&proc.0, Some(100), false);
+        source.read_failed(&Pid::from(42), "fd", e);
+        assert_eq!(source.take_errors(), 0);
+    }
+
+    #[test]
+    fn open_file_descriptors_permission_denied() {
+        let proc = FakeProc::new("fd-denied");
+        let pid = Pid::from(42);
+        // Root can read any directory, so the error is as the kernel returns for a process owned
+        // by another user
+        let mut source = ProcSnapshotSource::new(&proc.0, Some(100), false);
+        source.read_failed(&pid, "fd", io::ErrorKind::PermissionDenied.into());
+
+        assert_eq!(source.take_errors(), 0);
+        assert!(source.permission_denied.contains(&pid));
+    }
+
+    fn assert_stat_fields(stat: &ProcStat) {
+        assert_eq!(stat.state, 'S');
+        assert_eq!(stat.utime, 505);
+        assert_eq!(stat.stime, 606);
+        assert_eq!(stat.nice, 5);
+        assert_eq!(stat.num_threads, 9);
+        assert_eq!(stat.processor, Some(3));
+    }
+
+    #[test]
+    fn stat_comm_with_spaces() {
+        assert_stat_fields(&ProcStat::parse(&stat_line("foo bar")).unwrap());
+        assert_stat_fields(&ProcStat::parse(&stat_line("  ")).unwrap());
+    }
+
+    #[test]
+    fn stat_comm_with_parentheses() {
+        assert_stat_fields(&ProcStat::parse(&stat_line("weird )name")).unwrap());
+        assert_stat_fields(&ProcStat::parse(&stat_line("(weird )name)")).unwrap());
+    }
+
+    #[test]
+    fn stat_comm_ending_with_parenthesis() {
+        // A comm that looks like the end of the field followed by other fields, as a process can
+        // name itself with prctl(PR_SET_NAME)
+        let stat = ProcStat::parse("8638 (x) Z 1 2 3) R 8633 8638 8633 0 -1 4194304 80 0 0 0 12 34 0 0 20 0 1 0 811494 2703360 287 18446744073709551615 94442946064384 94442946084265 140730700662928 0 0 0 0 0 0 0 0 0 17 0 0 0 0 0 0 94442946100272 94442946101888 94443379056640 140730700666279 140730700666299 140730700666299 140730700668907 0\n").unwrap();
+        assert_eq!(stat.state, 'R');
+        assert_eq!(stat.minflt, 80);
+        assert_eq!(stat.majflt, 0);
+        assert_eq!(stat.utime, 12);
+        assert_eq!(stat.stime, 34);
+        assert_eq!(stat.nice, 0);
+        assert_eq!(stat.num_threads, 1);
+        assert_eq!(stat.processor, Some(0));
+        assert_eq!(stat.policy, Some(0));
+        assert_eq!(stat.delayacct_blkio_ticks, Some(0));
+    }
+
+    #[test]
+    fn stat_kernel_thread() {
+        let stat = ProcStat::parse("2 (kthreadd) S 0 0 0 0 -1 2129984 0 0 0 0 0 0 0 0 20 0 1 0 8 0 0 18446744073709551615 0 0 0 0 0 0 0 2147483647 0 1 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n").unwrap();
+        assert_eq!(stat.state, 'S');
+        assert_eq!(stat.jiffies(), 0);
+        assert_eq!(stat.num_threads, 1);
+        assert_eq!(stat.processor, Some(0));
+    }
+
+    #[test]
+    fn stat_page_faults() {
+        // Fields 11 (cminflt) and 13 (cmajflt) of the children are in between
+        let stat = ProcStat::parse(&stat_line("cat")).unwrap();
+        assert_eq!(stat.minflt, 1001);
+        assert_eq!(stat.majflt, 3003);
+    }
+
+    #[test]
+    fn stat_truncated_or_garbage() {
+        let truncated = stat_line("cat");
+        let truncated = &truncated[..truncated.find(" 505").unwrap()];
+        assert!(ProcStat::parse(truncated).is_err());
+        assert!(ProcStat::parse("1234 (cat").is_err());
+        assert!(ProcStat::parse("").is_err());
+        assert!(ProcStat::parse("not a stat line").is_err());
+        assert!(ProcStat::parse(&stat_line("cat").replace(" 505 ", " x ")).is_err());
+    }
+
+    /// A process to pass to `collect`, which only uses it for the fields sysinfo provides.
+    fn own_process() -> (System, Pid) {
+        let pid = sysinfo::get_current_pid().unwrap();
+        let mut sys = System::new();
+        sys.refresh_process(pid);
+        (sys, pid)
+    }
+
+    fn own_snapshot(source: &mut ProcSnapshotSource, proc_pid: &Pid) -> Option<ProcessSnapshot> {
+        let (sys, pid) = own_process();
+        source.collect(proc_pid, sys.process(pid).unwrap())
+    }
+
+    #[test]
+    fn collect_skips_exited_process() {
+        let proc = FakeProc::new("collect-exited");
+        let mut source = ProcSnapshotSource::new(&proc.0, Some(100), false);
+
+        assert!(own_snapshot(&mut source, &Pid::from(42)).is_none());
+        assert_eq!(source.take_errors(), 0);
+    }
+
+    #[test]
+    fn collect_skips_unparseable_stat() {
+        let proc = FakeProc::new("collect-garbage");
+        let pid = Pid::from(42);
+        proc.write(&pid, "stat", "not a stat line");
+        let mut source = ProcSnapshotSource::new(&proc.0, Some(100), false);
+
+        assert!(own_snapshot(&mut source, &pid).is_none());
+        assert_eq!(source.take_errors(), 1);
+    }
+
+    #[test]
+    fn collect_without_open_file_descriptors() {
+        let proc = FakeProc::new("collect-no-fd");
+        let pid = Pid::from(42);
+        proc.write(&pid, "stat", &stat_line("cat"));
+        let mut source = ProcSnapshotSource::new(&proc.0, Some(100), false);
+
+        let snapshot = own_snapshot(&mut source, &pid).unwrap();
+        assert_eq!(snapshot.cpu_time, 11.11);
+        // Unknown rather than none open
+        assert_eq!(snapshot.open_file_descriptors, None);
+        assert_eq!(snapshot.minor_page_faults, Some(1001));
+    }
+
+    #[test]
+    fn permission_denied_is_logged_once_per_process() {
+        let proc = FakeProc::new("collect-denied");
+        let pid = Pid::from(42);
+        let mut source = ProcSnapshotSource::new(&proc.0, Some(100), false);
+
+        own_snapshot(&mut source, &pid);
+        source.read_failed(&pid, "fd", io::ErrorKind::PermissionDenied.into());
+        source.read_failed(&pid, "environ", io::ErrorKind::PermissionDenied.into());
+        assert_eq!(source.permission_denied.len(), 1);
+        assert_eq!(source.take_errors(), 0);
+
+        // Kept while the process is collected, forgotten once it is not
+        source.end_collection();
+        assert!(source.permission_denied.contains(&pid));
+        source.end_collection();
+        assert!(source.permission_denied.is_empty());
+    }
+}
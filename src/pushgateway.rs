@@ -0,0 +1,172 @@
+use crate::encoding;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use std::time::Duration;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of pushes that can wait while an earlier push is being sent, further pushes are skipped
+/// (each replaces all metrics of the group, so only the latest matters).
+const MAX_PENDING_PUSHES: usize = 1;
+
+/// Encodes a label value for use in a Pushgateway grouping key URL.
+///
+/// Values that are empty or contain a `/` cannot be used as a path segment as is, so are base64
+/// encoded (marked by an `@base64` suffix on the label name).
+fn grouping_key_segment(name: &str, value: &str) -> String {
+    match value.is_empty() || value.contains('/') {
+        true => format!("{}@base64/{}", name, URL_SAFE.encode(value)),
+        false => format!("{}/{}", name, value),
+    }
+}
+
+/// Replaces all metrics in a group with metrics encoded in the Prometheus text format.
+async fn push(client: &reqwest::Client, url: &str, metrics: String) -> Result<()> {
+    client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, encoding::TEXT_CONTENT_TYPE)
+        .body(metrics)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to push metrics to {}", url))?;
+
+    log::debug!("Pushed metrics to {}", url);
+    Ok(())
+}
+
+/// Pushes metrics to a Prometheus Pushgateway.
+///
+/// Pushes after each collection are sent in the background, so that a slow or unavailable
+/// Pushgateway does not delay collection, only the final push is waited for.
+pub(crate) struct Pushgateway {
+    client: reqwest::Client,
+    url: String,
+    pushes: mpsc::Sender<String>,
+    pusher: JoinHandle<()>,
+}
+
+impl Pushgateway {
+    pub(crate) fn new(base_url: &str, job: &str, instance: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .context("Failed to create Pushgateway client")?;
+
+        let url = format!(
+            "{}/metrics/{}/{}",
+            base_url.trim_end_matches('/'),
+            grouping_key_segment("job", job),
+            grouping_key_segment("instance", instance)
+        );
+        log::info!("Pushing metrics to {}", url);
+
+        let (pushes, mut pending) = mpsc::channel::<String>(MAX_PENDING_PUSHES);
+        let pusher = {
+            let client = client.clone();
+            let url = url.clone();
+            tokio::spawn(async move {
+                while let Some(metrics) = pending.recv().await {
+                    if let Err(e) = push(&client, &url, metrics).await {
+                        log::warn!("{:#}", e);
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            client,
+            url,
+            pushes,
+            pusher,
+        })
+    }
+
+    /// Queues a push of metrics encoded in the Prometheus text format, which is skipped if the
+    /// Pushgateway is not keeping up.
+    pub(crate) fn push_in_background(&self, metrics: String) {
+        if self.pushes.try_send(metrics).is_err() {
+            log::warn!("Pushgateway is not keeping up, skipping a push");
+        }
+    }
+
+    /// Pushes the final metrics, after cancelling any push in the background so that it cannot
+    /// replace them with older ones.
+    pub(crate) async fn push_final(self, metrics: String) -> Result<()> {
+        self.pusher.abort();
+        push(&self.client, &self.url, metrics).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+
+    /// Starts a Pushgateway that records the bodies of pushes, and never responds to a push of
+    /// "slow".
+    fn start_pushgateway() -> (String, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let pushes = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let pushes = pushes.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let pushes = pushes.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let body = String::from_utf8(body.to_vec()).unwrap();
+                        let slow = body == "slow";
+                        pushes.lock().unwrap().push(body);
+                        if slow {
+                            std::future::pending::<()>().await;
+                        }
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn slow_pushgateway_does_not_block() {
+        crate::server::install_crypto_provider();
+        let (url, received) = start_pushgateway();
+        let pushgateway = Pushgateway::new(&url, "yuugi", "host").unwrap();
+
+        pushgateway.push_in_background("slow".to_string());
+        while received.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Queued behind the slow push, then skipped as the queue is full
+        pushgateway.push_in_background("queued".to_string());
+        pushgateway.push_in_background("skipped".to_string());
+
+        // Not sent after the final push, which would replace it
+        pushgateway.push_final("final".to_string()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*received.lock().unwrap(), ["slow", "final"]);
+    }
+
+    #[test]
+    fn grouping_key() {
+        assert_eq!(grouping_key_segment("job", "yuugi"), "job/yuugi");
+        assert_eq!(grouping_key_segment("instance", ""), "instance@base64/");
+        assert_eq!(
+            grouping_key_segment("instance", "a/b"),
+            "instance@base64/YS9i"
+        );
+    }
+}
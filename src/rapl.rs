@@ -0,0 +1,284 @@
+use crate::{
+    energy::{EnergyMeter, EnergyReading},
+    labels::HostLabels,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const POWERCAP_PATH: &str = "/sys/class/powercap";
+const CPU_PATH: &str = "/sys/devices/system/cpu";
+
+fn read_u64(path: &Path) -> io::Result<u64> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+struct Domain {
+    /// Name of the domain (e.g. "package-0", "core", "dram")
+    name: String,
+    /// Name of the powercap zone (e.g. "intel-rapl:0:1"), as domain names are only unique within
+    /// a package
+    zone: String,
+    /// Socket (physical package) the domain is in, `None` for domains that are not part of a
+    /// package (e.g. "psys")
+    socket: Option<usize>,
+    energy_path: PathBuf,
+    max_energy_range_uj: u64,
+    last_energy_uj: u64,
+    /// Whether a read has failed, so that a domain that cannot be read is only warned about once
+    read_failed: bool,
+}
+
+impl Domain {
+    fn new(path: &Path, zone: String) -> io::Result<Self> {
+        let name = fs::read_to_string(path.join("name"))?.trim().to_string();
+        let energy_path = path.join("energy_uj");
+        let max_energy_range_uj = read_u64(&path.join("max_energy_range_uj"))?;
+        let last_energy_uj = read_u64(&energy_path)?;
+
+        Ok(Self {
+            name,
+            zone,
+            socket: None,
+            energy_path,
+            max_energy_range_uj,
+            last_energy_uj,
+            read_failed: false,
+        })
+    }
+
+    /// Package domains contain all of the other CPU domains (apart from DRAM).
+    /// Other top level domains (e.g. "psys") measure more than the CPU.
+    fn is_package(&self) -> bool {
+        self.name.starts_with("package")
+    }
+
+    /// Socket of a package domain, from its name (e.g. "package-1" or "package-1-die-0").
+    fn package_socket(&self) -> Option<usize> {
+        self.name
+            .strip_prefix("package-")?
+            .split('-')
+            .next()?
+            .parse()
+            .ok()
+    }
+
+    /// Energy consumed since the last read in microjoules, accounting for the counter wrapping at
+    /// `max_energy_range_uj`.
+    fn read_delta_uj(&mut self) -> io::Result<u64> {
+        let energy_uj = read_u64(&self.energy_path)?;
+
+        let delta = if energy_uj >= self.last_energy_uj {
+            energy_uj - self.last_energy_uj
+        } else {
+            log::debug!("RAPL domain {} counter wrapped", self.name);
+            (self.max_energy_range_uj - self.last_energy_uj) + energy_uj
+        };
+
+        self.last_energy_uj = energy_uj;
+        Ok(delta)
+    }
+}
+
+/// Energy measurement via the Linux powercap RAPL interface.
+pub(crate) struct Rapl {
+    domains: Vec<Domain>,
+    /// Socket of each logical CPU
+    cpu_sockets: HashMap<usize, usize>,
+}
+
+/// Reads the socket (physical package) of each logical CPU from the CPU topology.
+fn read_cpu_sockets() -> HashMap<usize, usize> {
+    let entries = match fs::read_dir(CPU_PATH) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::info!("CPU topology not available: {}", e);
+            return HashMap::new();
+        }
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let cpu = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("cpu")?
+                .parse()
+                .ok()?;
+            let socket = read_u64(&entry.path().join("topology/physical_package_id")).ok()?;
+            Some((cpu, socket as usize))
+        })
+        .collect()
+}
+
+impl Rapl {
+    /// Finds all readable RAPL domains (`intel-rapl:N` and their subdomains `intel-rapl:N:M`),
+    /// returning `None` if there are no package domains.
+    pub(crate) fn discover() -> Option<Self> {
+        let entries = match fs::read_dir(POWERCAP_PATH) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::info!("Powercap not available: {}", e);
+                return None;
+            }
+        };
+
+        let mut domains = Vec::new();
+
+        for entry in entries.flatten() {
+            let zone = entry.file_name().to_string_lossy().to_string();
+            if !zone.starts_with("intel-rapl:") {
+                continue;
+            }
+
+            match Domain::new(&entry.path(), zone.clone()) {
+                Ok(domain) => {
+                    log::info!("Found RAPL domain {} ({})", domain.name, zone);
+                    domains.push(domain);
+                }
+                Err(e) => {
+                    log::warn!("Failed to read RAPL domain {}, err: {}", zone, e);
+                }
+            }
+        }
+
+        domains.sort_by(|a, b| a.zone.cmp(&b.zone));
+
+        // Subdomains (e.g. "intel-rapl:0:1") are in the same socket as their package domain
+        // ("intel-rapl:0")
+        let package_sockets: HashMap<String, usize> = domains
+            .iter()
+            .filter(|domain| domain.is_package())
+            .filter_map(|domain| Some((domain.zone.clone(), domain.package_socket()?)))
+            .collect();
+        for domain in domains.iter_mut() {
+            let package_zone = match domain.zone.match_indices(':').nth(1) {
+                Some((index, _)) => &domain.zone[..index],
+                None => &domain.zone,
+            };
+            domain.socket = package_sockets.get(package_zone).copied();
+        }
+
+        if !domains.iter().any(Domain::is_package) {
+            return None;
+        }
+
+        let cpu_sockets = read_cpu_sockets();
+        let sockets: HashSet<usize> = package_sockets.values().copied().collect();
+        if sockets.len() > 1 {
+            log::info!(
+                "Apportioning energy within each of {} sockets",
+                sockets.len()
+            );
+        }
+
+        Some(Self {
+            domains,
+            cpu_sockets,
+        })
+    }
+}
+
+impl EnergyMeter for Rapl {
+    fn name(&self) -> &'static str {
+        "rapl"
+    }
+
+    fn description(&self) -> &'static str {
+        "Total energy consumed by each RAPL domain"
+    }
+
+    /// CPU energy is the total of the package domains.
+    fn read_energy(&mut self) -> EnergyReading {
+        let mut reading = EnergyReading {
+            cpu: 0.0,
+            domains: Vec::new(),
+            sockets: Vec::new(),
+        };
+        let mut sockets = HashMap::<usize, f64>::new();
+        let mut unknown_socket = false;
+
+        for domain in self.domains.iter_mut() {
+            match domain.read_delta_uj() {
+                Ok(delta) => {
+                    let energy = (delta as f64) / 1_000_000.0;
+                    if domain.is_package() {
+                        reading.cpu += energy;
+                        match domain.socket {
+                            Some(socket) => *sockets.entry(socket).or_default() += energy,
+                            None => unknown_socket = true,
+                        }
+                    }
+                    let mut labels = vec![
+                        ("domain".to_string(), domain.name.clone()),
+                        ("zone".to_string(), domain.zone.clone()),
+                    ];
+                    if let Some(socket) = domain.socket {
+                        labels.push(("socket".to_string(), socket.to_string()));
+                    }
+                    reading.domains.push((HostLabels(labels), energy));
+                }
+                Err(e) if domain.read_failed => {
+                    log::debug!("Failed to read RAPL domain {}, err: {}", domain.name, e);
+                }
+                Err(e) => {
+                    log::warn!("Failed to read RAPL domain {}, err: {}", domain.name, e);
+                    domain.read_failed = true;
+                }
+            }
+        }
+
+        // Otherwise the energy of the sockets would not sum to the total
+        if !unknown_socket {
+            reading.sockets = sockets.into_iter().collect();
+        }
+
+        reading
+    }
+
+    fn cpu_socket(&self, cpu: usize) -> Option<usize> {
+        self.cpu_sockets.get(&cpu).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_domain_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("yuugi-rapl-{}", std::process::id()));
+        let zone = dir.join("intel-rapl:0");
+        fs::create_dir_all(&zone).unwrap();
+        fs::write(zone.join("name"), "package-0\n").unwrap();
+        fs::write(zone.join("max_energy_range_uj"), "262143328850\n").unwrap();
+        fs::write(zone.join("energy_uj"), "1000000\n").unwrap();
+        let mut domain = Domain::new(&zone, "intel-rapl:0".to_string()).unwrap();
+        domain.socket = domain.package_socket();
+        let mut rapl = Rapl {
+            domains: vec![domain],
+            cpu_sockets: HashMap::new(),
+        };
+
+        fs::write(zone.join("energy_uj"), "3000000\n").unwrap();
+        let reading = rapl.read_energy();
+        assert_eq!(reading.cpu, 2.0);
+        assert_eq!(reading.sockets, vec![(0, 2.0)]);
+        assert!(!rapl.domains[0].read_failed);
+
+        fs::remove_dir_all(&dir).unwrap();
+        for _ in 0..2 {
+            let reading = rapl.read_energy();
+            assert_eq!(reading.cpu, 0.0);
+            assert!(reading.domains.is_empty());
+        }
+        // Only warned about the first time
+        assert!(rapl.domains[0].read_failed);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::encoding;
+use anyhow::{anyhow, bail, Context, Result};
+use kagiyama::prometheus::{metrics::counter::Counter, registry::Registry};
+use prost::Message;
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{sync::mpsc, time::Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of times a write is attempted before its samples are dropped.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a failed write, doubled for each further retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Number of writes that can wait while an earlier write is being retried, further writes are
+/// dropped.
+const MAX_PENDING_WRITES: usize = 4;
+
+// Messages of version 1 of the remote write protocol, see
+// https://prometheus.io/docs/specs/prw/remote_write_spec/
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    /// Milliseconds since the Unix epoch
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Parses a label set (without the opening brace), returning the labels and the rest of the
+/// line after the closing brace.
+fn parse_labels(mut rest: &str) -> Result<(Vec<Label>, &str)> {
+    let mut labels = Vec::new();
+
+    loop {
+        rest = rest.trim_start_matches(',');
+        if let Some(rest) = rest.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+
+        let (name, value) = rest
+            .split_once("=\"")
+            .ok_or_else(|| anyhow!("expected a label"))?;
+
+        let mut unescaped = String::new();
+        let mut chars = value.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((index, '"')) => break index,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => unescaped.push('\n'),
+                    Some((_, c)) => unescaped.push(c),
+                    None => bail!("unterminated label value"),
+                },
+                Some((_, c)) => unescaped.push(c),
+                None => bail!("unterminated label value"),
+            }
+        };
+
+        labels.push(Label {
+            name: name.to_string(),
+            value: unescaped,
+        });
+        rest = &value[end + 1..];
+    }
+}
+
+/// Converts the samples of the OpenMetrics text encoding of a registry to time series, each with
+/// a single sample at the given time.
+///
+/// Lines that cannot be parsed are skipped (with a warning), so that a single odd series does not
+/// stop all others from being written. Also returns the number of lines skipped.
+fn parse_openmetrics(text: &str, timestamp: i64) -> (Vec<TimeSeries>, u64) {
+    let mut timeseries = Vec::new();
+    let mut skipped = 0;
+
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let parse = || -> Result<TimeSeries> {
+            let name_end = line
+                .find(['{', ' '])
+                .ok_or_else(|| anyhow!("expected a value"))?;
+            let (name, rest) = line.split_at(name_end);
+
+            let (mut labels, rest) = match rest.strip_prefix('{') {
+                Some(rest) => parse_labels(rest)?,
+                None => (Vec::new(), rest),
+            };
+            labels.push(Label {
+                name: "__name__".to_string(),
+                value: name.to_string(),
+            });
+            // Receivers require labels to be sorted by name
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+            let value = rest
+                .split_whitespace()
+                .next()
+                .ok_or_else(|| anyhow!("expected a value"))?;
+            let value = value
+                .parse()
+                .with_context(|| format!("invalid value \"{}\"", value))?;
+
+            Ok(TimeSeries {
+                labels,
+                samples: vec![Sample { value, timestamp }],
+            })
+        };
+        match parse() {
+            Ok(series) => timeseries.push(series),
+            Err(e) => {
+                log::warn!("Not writing \"{}\", failed to parse: {:#}", line, e);
+                skipped += 1;
+            }
+        }
+    }
+
+    (timeseries, skipped)
+}
+
+/// Credentials for HTTP basic authentication.
+pub(crate) struct BasicAuth {
+    pub(crate) username: String,
+    pub(crate) password: Option<String>,
+}
+
+/// Sends a write, retrying server errors and failures to connect with a backoff.
+///
+/// Client errors (other than rate limiting) are not retried, as the same write will fail again.
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    auth: Option<&BasicAuth>,
+    body: Vec<u8>,
+) -> Result<()> {
+    let mut attempt = 1;
+    let mut delay = RETRY_DELAY;
+
+    loop {
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .header(reqwest::header::CONTENT_ENCODING, "snappy")
+            .header(
+                reqwest::header::USER_AGENT,
+                concat!("yuugi/", env!("CARGO_PKG_VERSION")),
+            )
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body.clone());
+        if let Some(auth) = auth {
+            request = request.basic_auth(&auth.username, auth.password.as_ref());
+        }
+
+        let result = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let retry = match &result {
+            Ok(_) => return Ok(()),
+            Err(e) => e.status().is_none_or(|status| {
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }),
+        };
+
+        if !retry || attempt == MAX_ATTEMPTS {
+            return result
+                .map(|_| ())
+                .with_context(|| format!("Failed to write metrics to {}", url));
+        }
+        log::debug!(
+            "Failed to write metrics to {} (attempt {}), retrying in {:?}",
+            url,
+            attempt,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+        delay *= 2;
+    }
+}
+
+/// Periodically writes all metrics to an endpoint with the Prometheus remote write protocol.
+///
+/// Writes are sent in the background, so that a slow or unavailable endpoint does not delay
+/// collection. Samples that cannot be written are dropped rather than buffered.
+pub(crate) struct RemoteWrite {
+    interval: Duration,
+    last_write: Option<Instant>,
+    writes: mpsc::Sender<WriteRequest>,
+    dropped_samples: Counter,
+}
+
+impl RemoteWrite {
+    pub(crate) fn new(url: &str, auth: Option<BasicAuth>, interval: Duration) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .context("Failed to create remote write client")?;
+        log::info!("Writing metrics to {} every {:?}", url, interval);
+
+        let (writes, mut pending) = mpsc::channel::<WriteRequest>(MAX_PENDING_WRITES);
+        let dropped_samples = Counter::default();
+
+        let url = url.to_string();
+        let dropped = dropped_samples.clone();
+        tokio::spawn(async move {
+            while let Some(write) = pending.recv().await {
+                let samples = write.timeseries.len();
+                let body = snap::raw::Encoder::new()
+                    .compress_vec(&write.encode_to_vec())
+                    .expect("protobuf encoding should not exceed the snappy size limit");
+
+                match send(&client, &url, auth.as_ref(), body).await {
+                    Ok(()) => log::debug!("Wrote {} samples to {}", samples, url),
+                    Err(e) => {
+                        log::warn!("{:#}, dropping {} samples", e, samples);
+                        dropped.inc_by(samples as u64);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            interval,
+            last_write: None,
+            writes,
+            dropped_samples,
+        })
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "remote_write_dropped_samples",
+            "Total number of samples that could not be written to the remote write endpoint",
+            self.dropped_samples.clone(),
+        );
+    }
+
+    /// Queues a write of the current values of all metrics, if the interval has elapsed since
+    /// the previous write.
+    pub(crate) fn collected(&mut self, registry: &Registry) -> Result<()> {
+        let now = Instant::now();
+        if self
+            .last_write
+            .is_some_and(|last_write| now.duration_since(last_write) < self.interval)
+        {
+            return Ok(());
+        }
+        self.last_write = Some(now);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_millis() as i64);
+        let (timeseries, skipped) =
+            parse_openmetrics(&encoding::encode_openmetrics(registry)?, timestamp);
+        self.dropped_samples.inc_by(skipped);
+
+        let samples = timeseries.len();
+        if self.writes.try_send(WriteRequest { timeseries }).is_err() {
+            log::warn!(
+                "Remote write endpoint is not keeping up, dropping {} samples",
+                samples
+            );
+            self.dropped_samples.inc_by(samples as u64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn unparseable_lines_are_skipped() {
+        let text = r#"# TYPE cpu_time_seconds counter
+cpu_time_seconds_total{process_name="a \"b\"",pid="1"} 1.5
+cpu_time_seconds_total{process_name="unterminated} 2
+cpu_time_seconds_total{pid="2"} not-a-number
+scrapes_total 3
+# EOF
+"#;
+        let (timeseries, skipped) = parse_openmetrics(text, 1000);
+
+        assert_eq!(skipped, 2);
+        assert_eq!(
+            timeseries,
+            vec![
+                TimeSeries {
+                    labels: vec![
+                        label("__name__", "cpu_time_seconds_total"),
+                        label("pid", "1"),
+                        label("process_name", r#"a "b""#),
+                    ],
+                    samples: vec![Sample {
+                        value: 1.5,
+                        timestamp: 1000
+                    }],
+                },
+                TimeSeries {
+                    labels: vec![label("__name__", "scrapes_total")],
+                    samples: vec![Sample {
+                        value: 3.0,
+                        timestamp: 1000
+                    }],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn https_is_supported() {
+        crate::server::install_crypto_provider();
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+        // Nothing listens on port 1, so the request can only fail to connect, rather than
+        // because the scheme is not supported
+        let e = client
+            .post("https://127.0.0.1:1/api/v1/write")
+            .send()
+            .await
+            .unwrap_err();
+        let e = format!("{:?}", e);
+        assert!(e.contains("Connect"), "{}", e);
+        assert!(!e.contains("scheme is not http"), "{}", e);
+    }
+}
@@ -0,0 +1,585 @@
+use crate::{encoding, health::CollectorUp, ReadinessConditions};
+use anyhow::{Context, Result};
+use futures_util::{stream, StreamExt};
+use hyper::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    server::accept,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, StatusCode,
+};
+use kagiyama::{
+    prometheus::{
+        metrics::{
+            counter::Counter,
+            histogram::{exponential_buckets, Histogram},
+        },
+        registry::{Registry, Unit},
+    },
+    ReadinessProbe, Watcher,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    task::{self, Poll},
+};
+use strum::IntoEnumIterator;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Duration, Instant},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of TLS handshakes in progress at once.
+const MAX_PENDING_HANDSHAKES: usize = 16;
+
+/// Upper bounds of the scrape duration histogram buckets in seconds, from 1 ms to about 16 s
+/// (scrapes wait for a collection with --collect-on-scrape).
+fn scrape_duration_buckets() -> impl Iterator<Item = f64> {
+    exponential_buckets(0.001, 2.0, 15)
+}
+
+/// Readiness of the collector, reported via both the `collector_up` metric and the `/ready`
+/// endpoint, and optionally its liveness, reported via the `/alive` endpoint.
+#[derive(Clone)]
+pub(crate) struct Readiness {
+    probe: ReadinessProbe<ReadinessConditions>,
+    conditions: Arc<RwLock<HashMap<ReadinessConditions, bool>>>,
+    /// If set, yuugi is not alive once the last collection is stale
+    liveness: Option<CollectorUp>,
+}
+
+impl Readiness {
+    pub(crate) fn new(watcher: &Watcher<ReadinessConditions>) -> Self {
+        Self {
+            probe: watcher.readiness_probe(),
+            conditions: Arc::new(RwLock::new(
+                ReadinessConditions::iter().map(|c| (c, false)).collect(),
+            )),
+            liveness: None,
+        }
+    }
+
+    /// Makes liveness depend on collections keeping up.
+    pub(crate) fn with_liveness(self, collector_up: CollectorUp) -> Self {
+        Self {
+            liveness: Some(collector_up),
+            ..self
+        }
+    }
+
+    pub(crate) fn mark_ready(&mut self, condition: ReadinessConditions) {
+        self.probe.mark_ready(condition.clone());
+        self.conditions.write().unwrap().insert(condition, true);
+    }
+
+    fn is_ready(&self) -> bool {
+        self.conditions.read().unwrap().values().all(|ready| *ready)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.liveness
+            .as_ref()
+            .is_none_or(|collector_up| collector_up.is_alive())
+    }
+}
+
+/// Makes ring (which is also used for serving HTTPS) the crypto provider of the process, which
+/// the HTTP client used for remote write and the Pushgateway needs to connect to https URLs.
+pub(crate) fn install_crypto_provider() {
+    // Only fails if a provider is already installed
+    tokio_rustls::rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+}
+
+/// Loads a PEM encoded certificate chain and private key for serving HTTPS.
+pub(crate) fn load_tls(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificate {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read TLS key {}", key.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Request for a collection before a scrape is served, which is completed once the metrics are up
+/// to date.
+pub(crate) type CollectionRequest = oneshot::Sender<()>;
+
+/// A connection to the server, plain TCP, a unix socket or either of those with TLS.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Address on which to serve.
+#[derive(Debug, Clone)]
+pub(crate) enum Address {
+    Tcp(SocketAddr),
+    /// Path of a unix socket, only supported on unix platforms
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    async fn bind(address: &Address) -> Result<Self> {
+        match address {
+            Address::Tcp(address) => Ok(Listener::Tcp(
+                TcpListener::bind(address)
+                    .await
+                    .with_context(|| format!("Failed to listen on {}", address))?,
+            )),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(
+                    tokio::net::UnixListener::bind(path).with_context(|| {
+                        format!("Failed to listen on unix socket {}", path.display())
+                    })?,
+                ))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        }
+    }
+
+    fn poll_accept(&self, cx: &mut task::Context<'_>) -> Poll<std::io::Result<Stream>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+
+/// An accepted connection, before any TLS handshake.
+enum Stream {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Stream {
+    fn boxed(self) -> Box<dyn Connection> {
+        match self {
+            Stream::Tcp(stream) => Box::new(stream),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Box::new(stream),
+        }
+    }
+}
+
+/// Removes a socket left behind by a previous run that did not shut down cleanly, as binding
+/// fails if the path exists.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                anyhow::bail!("Unix socket {} is already in use", path.display());
+            }
+            log::info!("Removing stale unix socket {}", path.display());
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale unix socket {}", path.display()))
+        }
+        Ok(_) => anyhow::bail!("{} exists and is not a unix socket", path.display()),
+        Err(_) => Ok(()),
+    }
+}
+
+fn response(
+    status: StatusCode,
+    content_type: &'static str,
+    body: impl Into<Body>,
+) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap()
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so that a token cannot
+/// be guessed byte by byte from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks if the `Accept` header of a request prefers OpenMetrics over the Prometheus text format.
+fn accepts_openmetrics(request: &Request<Body>) -> bool {
+    let accept = match request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    // Quality of each media range, e.g. "application/openmetrics-text;version=1.0.0;q=0.5"
+    let quality = |media_type: &str| {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let range_type = params.next()?;
+                (range_type == media_type || range_type == "*/*").then(|| {
+                    params
+                        .find_map(|param| param.strip_prefix("q="))
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .unwrap_or(1.0)
+                })
+            })
+            .fold(0.0, f32::max)
+    };
+
+    let openmetrics = quality("application/openmetrics-text");
+    openmetrics > 0.0 && openmetrics > quality("text/plain")
+}
+
+/// Shared state used to handle requests.
+#[derive(Clone)]
+struct State {
+    metrics_path: Arc<str>,
+    watcher: Watcher<ReadinessConditions>,
+    readiness: Readiness,
+    auth_token: Option<Arc<str>>,
+    collection_requests: Option<mpsc::Sender<CollectionRequest>>,
+    scrapes: Counter,
+    scrape_duration: Histogram,
+}
+
+impl State {
+    /// Checks that a request has the bearer token, if one is required.
+    fn is_authorized(&self, request: &Request<Body>) -> bool {
+        match &self.auth_token {
+            Some(token) => request
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|value| constant_time_eq(value.as_bytes(), token.as_bytes()))
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// Waits for a collection, if collection is triggered by scrapes.
+    async fn collect(&self) {
+        if let Some(requests) = &self.collection_requests {
+            let (request, done) = oneshot::channel();
+            if requests.send(request).await.is_ok() {
+                // Fails if collection has stopped, in which case the last values are served
+                done.await.ok();
+            }
+        }
+    }
+}
+
+async fn handle(request: Request<Body>, state: State) -> Result<Response<Body>> {
+    let start = Instant::now();
+    let is_metrics = request.uri().path() == &*state.metrics_path;
+    // Requests without the token are not counted as scrapes
+    let is_scrape = is_metrics && state.is_authorized(&request);
+
+    if is_scrape {
+        state.collect().await;
+    }
+
+    let response = match request.uri().path() {
+        // Health endpoints are not authenticated so that probes do not need the token
+        _ if is_metrics && !state.is_authorized(&request) => {
+            let mut response = response(StatusCode::UNAUTHORIZED, "text/plain", "Unauthorized");
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            response
+        }
+        _ if is_metrics && accepts_openmetrics(&request) => response(
+            StatusCode::OK,
+            encoding::OPENMETRICS_CONTENT_TYPE,
+            encoding::encode_openmetrics(&state.watcher.metrics_registry())?,
+        ),
+        _ if is_metrics => response(
+            StatusCode::OK,
+            encoding::TEXT_CONTENT_TYPE,
+            encoding::encode_text(&state.watcher.metrics_registry())?,
+        ),
+        "/ready" => {
+            let status = match state.readiness.is_ready() {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            let conditions = serde_json::to_string(&*state.readiness.conditions.read().unwrap())?;
+            response(status, "application/json", conditions)
+        }
+        "/alive" => match state.readiness.is_alive() {
+            true => response(StatusCode::OK, "text/plain", "alive"),
+            false => response(StatusCode::SERVICE_UNAVAILABLE, "text/plain", "stale"),
+        },
+        _ => response(StatusCode::NOT_FOUND, "text/plain", "Not found"),
+    };
+
+    if is_scrape {
+        state.scrapes.inc();
+        state.scrape_duration.observe(start.elapsed().as_secs_f64());
+    }
+    Ok(response)
+}
+
+/// Serves the metrics registry (on the metrics path, usually `/metrics`) and health endpoints
+/// (`/ready` and `/alive`), over HTTPS if TLS is configured.
+///
+/// If an auth token is given, the metrics path requires it as a bearer token.
+/// If a collection request channel is given, each scrape of the metrics path waits for a
+/// collection.
+pub(crate) struct Server {
+    handle: JoinHandle<()>,
+    shutdown: oneshot::Sender<()>,
+    scrapes: Counter,
+    scrape_duration: Histogram,
+}
+
+impl Server {
+    pub(crate) async fn start(
+        address: Address,
+        metrics_path: String,
+        tls: Option<TlsAcceptor>,
+        auth_token: Option<String>,
+        watcher: Watcher<ReadinessConditions>,
+        readiness: Readiness,
+        collection_requests: Option<mpsc::Sender<CollectionRequest>>,
+    ) -> Result<Self> {
+        let listener = Listener::bind(&address).await?;
+        log::info!(
+            "Serving metrics over {} on {} at {}",
+            match tls {
+                Some(_) => "HTTPS",
+                None => "HTTP",
+            },
+            address,
+            metrics_path
+        );
+
+        let connections = stream::poll_fn(move |cx| listener.poll_accept(cx).map(Some))
+            .map(move |connection| {
+                let tls = tls.clone();
+                async move {
+                    let stream = match connection {
+                        Ok(stream) => stream.boxed(),
+                        Err(e) => {
+                            log::warn!("Failed to accept connection, err: {}", e);
+                            return None;
+                        }
+                    };
+
+                    match tls {
+                        Some(tls) => {
+                            match time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                                Ok(Ok(stream)) => Some(Box::new(stream) as Box<dyn Connection>),
+                                Ok(Err(e)) => {
+                                    log::debug!("TLS handshake failed, err: {}", e);
+                                    None
+                                }
+                                Err(_) => {
+                                    log::debug!("TLS handshake timed out");
+                                    None
+                                }
+                            }
+                        }
+                        None => Some(stream),
+                    }
+                }
+            })
+            .buffer_unordered(MAX_PENDING_HANDSHAKES)
+            .filter_map(|connection| async { connection.map(Ok::<_, Infallible>) });
+
+        let scrapes = Counter::default();
+        let scrape_duration = Histogram::new(scrape_duration_buckets());
+        let state = State {
+            metrics_path: metrics_path.into(),
+            watcher,
+            readiness,
+            auth_token: auth_token.map(Arc::from),
+            collection_requests,
+            scrapes: scrapes.clone(),
+            scrape_duration: scrape_duration.clone(),
+        };
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(request, state.clone()))) }
+        });
+
+        let (shutdown, shutdown_signal) = oneshot::channel();
+        let server = hyper::Server::builder(accept::from_stream(connections))
+            .serve(make_service)
+            .with_graceful_shutdown(async {
+                shutdown_signal.await.ok();
+            });
+
+        let handle = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                log::error!("Error running metrics server, err: {}", e);
+            }
+
+            if let Address::Unix(path) = &address {
+                if let Err(e) = std::fs::remove_file(path) {
+                    log::warn!(
+                        "Failed to remove unix socket {}, err: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        });
+
+        Ok(Self {
+            handle,
+            shutdown,
+            scrapes,
+            scrape_duration,
+        })
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "yuugi_scrapes",
+            "Total number of scrapes of the metrics path",
+            self.scrapes.clone(),
+        );
+        registry.register_with_unit(
+            "yuugi_scrape_duration",
+            "Time taken to respond to scrapes of the metrics path",
+            Unit::Seconds,
+            self.scrape_duration.clone(),
+        );
+    }
+
+    /// Stops accepting connections and waits for any requests in progress to complete.
+    pub(crate) async fn stop(self) -> Result<()> {
+        self.shutdown.send(()).ok();
+        self.handle.await.context("Metrics server task failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().uri("/metrics");
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn accept_header() {
+        for (accept, openmetrics) in [
+            (None, false),
+            (Some("*/*"), false),
+            (Some("text/plain"), false),
+            (Some("application/openmetrics-text"), true),
+            (Some("application/openmetrics-text;version=1.0.0"), true),
+            (
+                Some("application/openmetrics-text; version=1.0.0; charset=utf-8"),
+                true,
+            ),
+            // As sent by Prometheus
+            (
+                Some("application/openmetrics-text;version=1.0.0;q=0.5,application/openmetrics-text;version=0.0.1;q=0.4,text/plain;version=0.0.4;q=0.3,*/*;q=0.2"),
+                true,
+            ),
+            (
+                Some("application/openmetrics-text;q=0.3,text/plain;q=0.5"),
+                false,
+            ),
+            (Some("application/openmetrics-text;q=0"), false),
+            (Some("application/openmetrics-text;q=0.5,*/*"), false),
+        ] {
+            assert_eq!(
+                accepts_openmetrics(&request(accept)),
+                openmetrics,
+                "{:?}",
+                accept
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn content_type() {
+        let watcher = Watcher::<ReadinessConditions>::default();
+        let state = State {
+            metrics_path: "/metrics".into(),
+            readiness: Readiness::new(&watcher),
+            watcher,
+            auth_token: None,
+            collection_requests: None,
+            scrapes: Counter::default(),
+            scrape_duration: Histogram::new(scrape_duration_buckets()),
+        };
+
+        for (accept, content_type) in [
+            (None, encoding::TEXT_CONTENT_TYPE),
+            (Some("*/*"), encoding::TEXT_CONTENT_TYPE),
+            (
+                Some("application/openmetrics-text;version=1.0.0"),
+                encoding::OPENMETRICS_CONTENT_TYPE,
+            ),
+            (
+                Some("application/openmetrics-text;q=0.3,text/plain;q=0.5"),
+                encoding::TEXT_CONTENT_TYPE,
+            ),
+        ] {
+            let response = handle(request(accept), state.clone()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[CONTENT_TYPE],
+                content_type,
+                "{:?}",
+                accept
+            );
+        }
+        assert_eq!(state.scrapes.get(), 4);
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+
+#[cfg(unix)]
+use {
+    anyhow::Context,
+    tokio::signal::unix::{signal, Signal, SignalKind},
+};
+
+/// Signals that request a graceful shutdown.
+pub(crate) struct ShutdownSignals {
+    #[cfg(unix)]
+    sigint: Signal,
+    #[cfg(unix)]
+    sigterm: Signal,
+}
+
+impl ShutdownSignals {
+    #[cfg(unix)]
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt()).context("Failed to register SIGINT handler")?,
+            sigterm: signal(SignalKind::terminate())
+                .context("Failed to register SIGTERM handler")?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Waits for a shutdown signal, returning its name.
+    #[cfg(unix)]
+    pub(crate) async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigint.recv() => "SIGINT",
+            _ = self.sigterm.recv() => "SIGTERM",
+        }
+    }
+
+    /// Waits for a shutdown signal, returning its name.
+    #[cfg(not(unix))]
+    pub(crate) async fn recv(&mut self) -> &'static str {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => "Ctrl-C",
+            Err(e) => {
+                log::warn!("Failed to listen for Ctrl-C, err: {}", e);
+                std::future::pending().await
+            }
+        }
+    }
+}
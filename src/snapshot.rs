@@ -0,0 +1,218 @@
+use anyhow::{bail, Result};
+use std::{collections::HashMap, path::Path, time::Instant};
+use sysinfo::{Pid, Process, ProcessExt};
+
+/// Everything about a process that is needed for metrics, collected once per collection.
+///
+/// Fields that are `None` are not available on the platform, or could not be read for the
+/// process.
+#[derive(Clone, Default)]
+pub(crate) struct ProcessSnapshot {
+    /// Seconds since the Unix epoch
+    pub(crate) start_time: u64,
+    /// Total CPU time used by the process in seconds
+    pub(crate) cpu_time: f64,
+    /// CPU time used in user mode in seconds
+    pub(crate) cpu_user_time: Option<f64>,
+    /// CPU time used in kernel mode in seconds
+    pub(crate) cpu_kernel_time: Option<f64>,
+    pub(crate) memory_resident: u64,
+    pub(crate) memory_virtual: u64,
+    pub(crate) memory_swap: Option<u64>,
+    pub(crate) disk_read_bytes: u64,
+    pub(crate) disk_written_bytes: u64,
+    pub(crate) num_threads: Option<u64>,
+    /// Index of the logical CPU the process last ran on
+    pub(crate) last_cpu: Option<usize>,
+    pub(crate) nice: Option<i64>,
+    /// Name of the scheduling policy (e.g. "other", "fifo")
+    pub(crate) sched_policy: Option<String>,
+    /// Name of the process state (e.g. "running", "zombie")
+    pub(crate) state: Option<String>,
+    pub(crate) open_file_descriptors: Option<u64>,
+    pub(crate) voluntary_context_switches: Option<u64>,
+    pub(crate) involuntary_context_switches: Option<u64>,
+    pub(crate) minor_page_faults: Option<u64>,
+    pub(crate) major_page_faults: Option<u64>,
+    /// Total time spent waiting for block IO in seconds
+    pub(crate) block_io_delay: Option<f64>,
+    /// Only collected with --per-thread
+    pub(crate) threads: Vec<ThreadSnapshot>,
+}
+
+/// CPU time used by a thread of a process.
+#[derive(Clone)]
+pub(crate) struct ThreadSnapshot {
+    pub(crate) tid: u64,
+    /// Name of the thread (its `comm`), which defaults to that of the process
+    pub(crate) name: String,
+    /// Total CPU time used by the thread in seconds
+    pub(crate) cpu_time: f64,
+}
+
+impl ProcessSnapshot {
+    /// A snapshot with the fields sysinfo provides on every platform, and no platform specific
+    /// fields.
+    pub(crate) fn new(process: &Process, cpu_time: f64) -> Self {
+        let disk_usage = process.disk_usage();
+
+        Self {
+            start_time: process.start_time(),
+            cpu_time,
+            cpu_user_time: None,
+            cpu_kernel_time: None,
+            memory_resident: process.memory(),
+            memory_virtual: process.virtual_memory(),
+            memory_swap: None,
+            disk_read_bytes: disk_usage.total_read_bytes,
+            disk_written_bytes: disk_usage.total_written_bytes,
+            num_threads: None,
+            last_cpu: None,
+            nice: None,
+            sched_policy: None,
+            state: None,
+            open_file_descriptors: None,
+            voluntary_context_switches: None,
+            involuntary_context_switches: None,
+            minor_page_faults: None,
+            major_page_faults: None,
+            block_io_delay: None,
+            threads: Vec::new(),
+        }
+    }
+}
+
+/// A platform specific source of process snapshots.
+pub(crate) trait SnapshotSource {
+    /// Collects a snapshot of a process, `None` if it could not be collected (e.g. the process
+    /// has exited).
+    fn collect(&mut self, pid: &Pid, process: &Process) -> Option<ProcessSnapshot>;
+
+    /// Called after every process has been collected in a collection.
+    fn end_collection(&mut self) {}
+
+    /// Number of unexpected failures to collect a process (i.e. not because it has exited) since
+    /// the last call.
+    fn take_errors(&mut self) -> u64 {
+        0
+    }
+
+    /// Details of the source, added to the system info metric.
+    fn info(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// Where the CPU time (and other platform specific details) of processes is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum CpuTimeSource {
+    /// /proc/[pid]/stat (only available on Linux)
+    Proc,
+    /// Integrated from the CPU usage reported by sysinfo
+    Sysinfo,
+}
+
+impl Default for CpuTimeSource {
+    fn default() -> Self {
+        match cfg!(target_os = "linux") {
+            true => Self::Proc,
+            false => Self::Sysinfo,
+        }
+    }
+}
+
+impl CpuTimeSource {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Proc => "proc",
+            Self::Sysinfo => "sysinfo",
+        }
+    }
+
+    /// The path of the proc filesystem, number of clock ticks per second and whether to collect
+    /// threads are only used by the proc source, the number of clock ticks is determined
+    /// automatically if not given.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    pub(crate) fn snapshot_source(
+        &self,
+        proc_path: &Path,
+        clock_ticks_per_second: Option<u64>,
+        per_thread: bool,
+    ) -> Result<Box<dyn SnapshotSource>> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::Proc => {
+                if let Err(e) = std::fs::metadata(proc_path.join("stat")) {
+                    bail!(
+                        "{} is not readable ({}), use --cpu-time-source sysinfo instead",
+                        proc_path.display(),
+                        e
+                    );
+                }
+                Ok(Box::new(crate::procfs::ProcSnapshotSource::new(
+                    proc_path,
+                    clock_ticks_per_second,
+                    per_thread,
+                )))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Self::Proc => bail!("The proc CPU time source is only available on Linux"),
+            Self::Sysinfo => Ok(Box::<SysinfoSnapshotSource>::default()),
+        }
+    }
+}
+
+struct TrackedProcess {
+    start_time: u64,
+    last_seen: Instant,
+    cpu_time: f64,
+    generation: u64,
+}
+
+/// CPU time integrated from the CPU usage sysinfo calculates at each refresh, for platforms
+/// without `/proc` (e.g. Windows and macOS) or where it cannot be read.
+///
+/// CPU time used before a process is first seen is not known, so the CPU time of every process is
+/// counted from the first collection it is seen in.
+#[derive(Default)]
+pub(crate) struct SysinfoSnapshotSource {
+    processes: HashMap<Pid, TrackedProcess>,
+    generation: u64,
+}
+
+impl SnapshotSource for SysinfoSnapshotSource {
+    fn collect(&mut self, pid: &Pid, process: &Process) -> Option<ProcessSnapshot> {
+        let now = Instant::now();
+        let generation = self.generation;
+
+        let tracked = self
+            .processes
+            .entry(*pid)
+            .and_modify(|tracked| {
+                if tracked.start_time == process.start_time() {
+                    let elapsed = now.duration_since(tracked.last_seen).as_secs_f64();
+                    tracked.cpu_time += (process.cpu_usage() as f64 / 100.0) * elapsed;
+                } else {
+                    tracked.start_time = process.start_time();
+                    tracked.cpu_time = 0.0;
+                }
+                tracked.last_seen = now;
+                tracked.generation = generation;
+            })
+            .or_insert_with(|| TrackedProcess {
+                start_time: process.start_time(),
+                last_seen: now,
+                cpu_time: 0.0,
+                generation,
+            });
+
+        Some(ProcessSnapshot::new(process, tracked.cpu_time))
+    }
+
+    fn end_collection(&mut self) {
+        let generation = self.generation;
+        self.processes
+            .retain(|_, tracked| tracked.generation == generation);
+        self.generation += 1;
+    }
+}
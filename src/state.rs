@@ -0,0 +1,184 @@
+use crate::{labels::Labels, metrics::EnergyUnit};
+use anyhow::{bail, Context, Result};
+use kagiyama::prometheus::{
+    metrics::{counter::Counter, family::Family},
+    registry::{Registry, Unit},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicU64,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+#[derive(Serialize, Deserialize)]
+struct SeriesState {
+    labels: Labels,
+    energy: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    /// Name of the unit the energies are in
+    energy_unit: String,
+    series: Vec<SeriesState>,
+}
+
+/// Energy of each series that is persisted to a file, so that it keeps increasing across
+/// restarts of both yuugi and the processes in the series (e.g. for billing).
+///
+/// Series are identified by their labels other than pid, ppid and cmdline, which change when a
+/// process is restarted. Series are never removed, so their energy is kept for as long as the
+/// file is.
+pub(crate) struct PersistentEnergy {
+    path: PathBuf,
+    energy_unit: EnergyUnit,
+    interval: Duration,
+    last_write: Instant,
+    energy: Family<Labels, Counter<f64, AtomicU64>>,
+    totals: HashMap<Labels, f64>,
+}
+
+impl PersistentEnergy {
+    /// Loads the energy of each series from a file, starting from zero if it does not exist or
+    /// cannot be read.
+    pub(crate) fn load(path: &Path, energy_unit: EnergyUnit, interval: Duration) -> Self {
+        let totals = match Self::read(path, energy_unit) {
+            Ok(totals) => {
+                log::info!(
+                    "Loaded energy of {} series from state file {}",
+                    totals.len(),
+                    path.display()
+                );
+                totals
+            }
+            Err(e) => {
+                log::warn!("{:#}, starting from zero", e);
+                HashMap::new()
+            }
+        };
+
+        let energy = Family::<Labels, Counter<f64, AtomicU64>>::default();
+        for (labels, total) in &totals {
+            energy.get_or_create(labels).inc_by(*total);
+        }
+
+        Self {
+            path: path.to_path_buf(),
+            energy_unit,
+            interval,
+            last_write: Instant::now(),
+            energy,
+            totals,
+        }
+    }
+
+    fn read(path: &Path, energy_unit: EnergyUnit) -> Result<HashMap<Labels, f64>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                bail!("State file {} does not exist", path.display())
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read state file {}", path.display()))
+            }
+        };
+        let state: State = serde_json::from_str(&contents)
+            .with_context(|| format!("Malformed state file {}", path.display()))?;
+
+        if state.energy_unit != energy_unit.name() {
+            bail!(
+                "State file {} is in {} rather than {}",
+                path.display(),
+                state.energy_unit,
+                energy_unit.name()
+            );
+        }
+        if let Some(series) = state
+            .series
+            .iter()
+            .find(|series| !series.energy.is_finite() || series.energy < 0.0)
+        {
+            bail!(
+                "State file {} has an invalid energy of {} for {:?}",
+                path.display(),
+                series.energy,
+                series.labels
+            );
+        }
+
+        Ok(state
+            .series
+            .into_iter()
+            .map(|series| (series.labels, series.energy))
+            .collect())
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "persistent_energy",
+            "Energy attributed to processes, persisted across restarts of yuugi and of the processes",
+            Unit::Other(self.energy_unit.name().to_string()),
+            self.energy.clone(),
+        );
+    }
+
+    /// Adds energy attributed to the processes of a series.
+    pub(crate) fn add(&mut self, labels: &Labels, energy: f64) {
+        let labels = Labels {
+            pid: None,
+            ppid: None,
+            cmdline: None,
+            ..labels.clone()
+        };
+        self.energy.get_or_create(&labels).inc_by(energy);
+        *self.totals.entry(labels).or_default() += energy;
+    }
+
+    /// Writes the state file, if the interval has elapsed since the previous write.
+    pub(crate) fn collected(&mut self) -> Result<()> {
+        if self.last_write.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Writes the state file.
+    ///
+    /// The file is replaced in a single step, so that it is not left partially written if yuugi
+    /// is stopped while writing.
+    pub(crate) fn save(&mut self) -> Result<()> {
+        self.last_write = Instant::now();
+
+        let state = State {
+            energy_unit: self.energy_unit.name().to_string(),
+            series: self
+                .totals
+                .iter()
+                .map(|(labels, energy)| SeriesState {
+                    labels: labels.clone(),
+                    energy: *energy,
+                })
+                .collect(),
+        };
+        let contents = serde_json::to_string(&state).context("Failed to encode state")?;
+
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, contents)
+            .and_then(|()| fs::rename(&temporary, &self.path))
+            .with_context(|| format!("Failed to write state file {}", self.path.display()))?;
+
+        log::debug!(
+            "Wrote energy of {} series to state file {}",
+            state.series.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
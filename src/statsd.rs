@@ -0,0 +1,117 @@
+use crate::{
+    kubernetes::PodMetadata,
+    labels::Labels,
+    metrics::{Descriptor, Kind, Sample},
+};
+use anyhow::{anyhow, Context, Result};
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// Maximum size of a datagram, small enough to avoid fragmentation on a typical network.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Makes a tag value safe to use in a DogStatsD tag list.
+fn tag_value(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+/// Emits per process metrics to a StatsD server, with labels as DogStatsD tags.
+pub(crate) struct StatsdExporter {
+    socket: UdpSocket,
+    /// Tags of every metric, for the host rather than a process
+    common_tags: String,
+    /// Exported metrics, with the index of their value in [`Sample::values`]
+    descriptors: Vec<(usize, Descriptor)>,
+}
+
+impl StatsdExporter {
+    pub(crate) fn new(
+        address: &str,
+        hostname: &str,
+        pod: &PodMetadata,
+        labels: &[(String, String)],
+        descriptors: Vec<(usize, Descriptor)>,
+    ) -> Result<Self> {
+        let address = address
+            .to_socket_addrs()
+            .with_context(|| format!("Invalid StatsD address \"{}\"", address))?
+            .next()
+            .ok_or_else(|| anyhow!("StatsD address \"{}\" did not resolve", address))?;
+
+        let bind_address = match address.is_ipv4() {
+            true => "0.0.0.0:0",
+            false => "[::]:0",
+        };
+        let socket = UdpSocket::bind(bind_address).context("Failed to create StatsD socket")?;
+        socket
+            .connect(address)
+            .with_context(|| format!("Failed to connect to StatsD server {}", address))?;
+        log::info!("Sending metrics to StatsD server {}", address);
+
+        Ok(Self {
+            socket,
+            common_tags: std::iter::once(("hostname", hostname))
+                .chain(pod.labels())
+                .chain(
+                    labels
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str())),
+                )
+                .map(|(name, value)| format!("{}:{}", name, tag_value(value)))
+                .collect::<Vec<_>>()
+                .join(","),
+            descriptors,
+        })
+    }
+
+    /// Sends the samples from a collection, counters as the increase since the previous
+    /// collection and gauges as their current value.
+    ///
+    /// Lines are batched into as few datagrams as possible.
+    pub(crate) fn send<'a>(&self, samples: impl IntoIterator<Item = (&'a Labels, &'a Sample)>) {
+        let mut datagram = String::new();
+
+        for (labels, sample) in samples {
+            let mut tags = self.common_tags.clone();
+            for (name, value) in labels.pairs() {
+                tags.push_str(&format!(",{}:{}", name, tag_value(value)));
+            }
+
+            let values = sample.values();
+            for (index, descriptor) in &self.descriptors {
+                let value = match values[*index] {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                let name = match descriptor.unit {
+                    Some(unit) => format!("{}_{}", descriptor.name, unit),
+                    None => descriptor.name.to_string(),
+                };
+                let kind = match descriptor.kind {
+                    Kind::Counter => "c",
+                    Kind::Gauge => "g",
+                };
+                let line = format!("{}:{}|{}|#{}", name, value, kind, tags);
+
+                if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                    self.send_datagram(&datagram);
+                    datagram.clear();
+                }
+                if !datagram.is_empty() {
+                    datagram.push('\n');
+                }
+                datagram.push_str(&line);
+            }
+        }
+
+        if !datagram.is_empty() {
+            self.send_datagram(&datagram);
+        }
+    }
+
+    fn send_datagram(&self, datagram: &str) {
+        if let Err(e) = self.socket.send(datagram.as_bytes()) {
+            log::warn!("Failed to send metrics to StatsD server, err: {}", e);
+        }
+    }
+}
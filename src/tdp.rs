@@ -0,0 +1,217 @@
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+/// TDP in Watts of common CPU models, keyed by the words that identify the model in the brand
+/// string reported by the CPU (e.g. "Intel(R) Core(TM) i7-8700K CPU @ 3.70GHz").
+const TDP_TABLE: &[(&str, f64)] = &[
+    // Intel desktop
+    ("i9-13900K", 125.0),
+    ("i7-13700K", 125.0),
+    ("i5-13600K", 125.0),
+    ("i5-13400", 65.0),
+    ("i9-12900K", 125.0),
+    ("i7-12700K", 125.0),
+    ("i7-12700", 65.0),
+    ("i5-12600K", 125.0),
+    ("i5-12400", 65.0),
+    ("i9-11900K", 125.0),
+    ("i7-11700K", 125.0),
+    ("i5-11400", 65.0),
+    ("i9-10900K", 125.0),
+    ("i7-10700K", 125.0),
+    ("i7-10700", 65.0),
+    ("i5-10400", 65.0),
+    ("i9-9900K", 95.0),
+    ("i7-9700K", 95.0),
+    ("i5-9600K", 95.0),
+    ("i7-8700K", 95.0),
+    ("i7-8700", 65.0),
+    ("i5-8400", 65.0),
+    ("i7-7700K", 91.0),
+    ("i7-7700", 65.0),
+    ("i7-6700K", 91.0),
+    ("i7-6700", 65.0),
+    // Intel mobile
+    ("i7-12700H", 45.0),
+    ("i7-1260P", 28.0),
+    ("i7-1255U", 15.0),
+    ("i7-11800H", 45.0),
+    ("i7-1185G7", 28.0),
+    ("i7-1165G7", 28.0),
+    ("i5-1135G7", 28.0),
+    ("i7-10750H", 45.0),
+    ("i7-10510U", 15.0),
+    ("i5-10210U", 15.0),
+    ("i7-9750H", 45.0),
+    ("i7-8750H", 45.0),
+    ("i7-8550U", 15.0),
+    ("i5-8250U", 15.0),
+    // Intel server
+    ("Platinum 8380", 270.0),
+    ("Gold 6348", 235.0),
+    ("Gold 6248", 150.0),
+    ("Gold 5218", 125.0),
+    ("Silver 4210", 85.0),
+    // AMD desktop
+    ("Ryzen 9 7950X", 170.0),
+    ("Ryzen 9 7900X", 170.0),
+    ("Ryzen 7 7700X", 105.0),
+    ("Ryzen 5 7600X", 105.0),
+    ("Ryzen 9 5950X", 105.0),
+    ("Ryzen 9 5900X", 105.0),
+    ("Ryzen 7 5800X", 105.0),
+    ("Ryzen 5 5600X", 65.0),
+    ("Ryzen 9 3950X", 105.0),
+    ("Ryzen 9 3900X", 105.0),
+    ("Ryzen 7 3700X", 65.0),
+    ("Ryzen 5 3600", 65.0),
+    // AMD mobile
+    ("Ryzen 7 7840U", 28.0),
+    ("Ryzen 7 6800U", 28.0),
+    ("Ryzen 7 5800H", 45.0),
+    ("Ryzen 7 5800U", 15.0),
+    ("Ryzen 7 4800U", 15.0),
+    // AMD server
+    ("EPYC 9654", 360.0),
+    ("EPYC 7763", 280.0),
+    ("EPYC 7742", 225.0),
+    ("EPYC 7543", 225.0),
+    ("EPYC 7313", 155.0),
+];
+
+/// Whether all the words of a model appear consecutively in a brand string.
+fn matches_model(brand: &str, model: &str) -> bool {
+    let brand: Vec<&str> = brand.split_whitespace().collect();
+    let model: Vec<&str> = model.split_whitespace().collect();
+    !model.is_empty() && brand.windows(model.len()).any(|words| words == model)
+}
+
+/// Finds the TDP of the CPU with the given brand string, if it is a known model.
+pub(crate) fn lookup(brand: &str) -> Option<f64> {
+    TDP_TABLE
+        .iter()
+        .find(|(model, _)| matches_model(brand, model))
+        .map(|(_, tdp)| *tdp)
+}
+
+enum Pattern {
+    /// Words that identify the model, matched in the same way as the built in table
+    Model(String),
+    Regex(Regex),
+}
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    model: String,
+    power: f64,
+}
+
+/// A user provided table of die powers, used in place of the built in table.
+///
+/// Each entry is a model (as in the built in table) or a regular expression between slashes
+/// (e.g. `/EPYC 7\d{2}3/`), matched against the brand string. The first matching entry is used.
+pub(crate) struct PowerDb {
+    entries: Vec<(String, Pattern, f64)>,
+}
+
+impl PowerDb {
+    /// Loads a CSV (`.csv`, with `model,power` lines) or JSON (`.json`, with an array of
+    /// `{"model": ..., "power": ...}` objects) file.
+    ///
+    /// Blank lines and lines starting with `#` in a CSV file are ignored, as is a `model,power`
+    /// header.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read power database {}", path.display()))?;
+
+        let entries: Vec<(String, f64)> = match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => contents
+                .lines()
+                .enumerate()
+                .map(|(index, line)| (index, line.trim()))
+                .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+                .filter(|(_, line)| !line.eq_ignore_ascii_case("model,power"))
+                .map(|(index, line)| {
+                    // Brand strings do not contain commas, but a regular expression may
+                    let (model, power) = line
+                        .rsplit_once(',')
+                        .ok_or_else(|| anyhow!("expected model,power"))
+                        .and_then(|(model, power)| {
+                            let power = power.trim().parse().context("invalid power")?;
+                            Ok((model.trim().to_string(), power))
+                        })
+                        .with_context(|| {
+                            format!(
+                                "Malformed line {} of power database {}",
+                                index + 1,
+                                path.display()
+                            )
+                        })?;
+                    Ok((model, power))
+                })
+                .collect::<Result<_>>()?,
+            Some("json") => serde_json::from_str::<Vec<JsonEntry>>(&contents)
+                .with_context(|| format!("Malformed power database {}", path.display()))?
+                .into_iter()
+                .map(|entry| (entry.model, entry.power))
+                .collect(),
+            _ => bail!(
+                "Unknown power database format for {} (expected .csv or .json)",
+                path.display()
+            ),
+        };
+
+        let entries = entries
+            .into_iter()
+            .map(|(model, power)| {
+                let parse = || -> Result<(Pattern, f64)> {
+                    if !power.is_finite() || power <= 0.0 {
+                        bail!("power must be greater than 0 W");
+                    }
+                    let pattern = match model
+                        .strip_prefix('/')
+                        .and_then(|model| model.strip_suffix('/'))
+                    {
+                        Some(pattern) => Pattern::Regex(
+                            Regex::new(pattern).context("invalid regular expression")?,
+                        ),
+                        None if model.split_whitespace().next().is_none() => {
+                            bail!("model must not be empty")
+                        }
+                        None => Pattern::Model(model.clone()),
+                    };
+                    Ok((pattern, power))
+                };
+                let (pattern, power) = parse().with_context(|| {
+                    format!(
+                        "Invalid entry \"{}\" in power database {}",
+                        model,
+                        path.display()
+                    )
+                })?;
+                Ok((model, pattern, power))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        log::info!(
+            "Loaded {} entries from power database {}",
+            entries.len(),
+            path.display()
+        );
+        Ok(Self { entries })
+    }
+
+    /// Finds the first entry matching the given brand string, returning the entry as written in
+    /// the file and its power.
+    pub(crate) fn lookup(&self, brand: &str) -> Option<(&str, f64)> {
+        self.entries
+            .iter()
+            .find(|(_, pattern, _)| match pattern {
+                Pattern::Model(model) => matches_model(brand, model),
+                Pattern::Regex(regex) => regex.is_match(brand),
+            })
+            .map(|(entry, _, power)| (entry.as_str(), *power))
+    }
+}
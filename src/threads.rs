@@ -0,0 +1,90 @@
+use crate::{
+    labels::{self, Labels},
+    snapshot::ProcessSnapshot,
+};
+use kagiyama::prometheus::{
+    encoding::{EncodeLabelSet, LabelSetEncoder},
+    metrics::{counter::Counter, family::Family},
+    registry::{Registry, Unit},
+};
+use std::{collections::HashMap, sync::atomic::AtomicU64};
+
+/// Labels of a thread, the labels of the series of its process and the thread ID and name.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct ThreadLabels {
+    labels: Labels,
+    tid: String,
+    thread_name: String,
+}
+
+impl EncodeLabelSet for ThreadLabels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        let mut pairs = self.labels.pairs();
+        pairs.push(("tid", &self.tid));
+        pairs.push(("thread_name", &self.thread_name));
+        labels::encode_pairs(pairs, encoder)
+    }
+}
+
+/// CPU time of every thread of the exported processes.
+#[derive(Default)]
+pub(crate) struct ThreadMetrics {
+    cpu_time: Family<ThreadLabels, Counter<f64, AtomicU64>>,
+    /// CPU time of each thread in the previous collection
+    previous: HashMap<ThreadLabels, f64>,
+}
+
+impl ThreadMetrics {
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "thread_cpu_time",
+            "Total CPU time used by each thread",
+            Unit::Seconds,
+            self.cpu_time.clone(),
+        );
+    }
+
+    /// Updates the CPU time of the threads of each process, and removes the series of threads
+    /// that have exited (or whose process is no longer exported).
+    pub(crate) fn update<'a>(
+        &mut self,
+        processes: impl Iterator<Item = (&'a Labels, &'a ProcessSnapshot)>,
+    ) {
+        let mut current = HashMap::new();
+
+        for (labels, snapshot) in processes {
+            for thread in &snapshot.threads {
+                let thread_labels = ThreadLabels {
+                    labels: labels.clone(),
+                    tid: thread.tid.to_string(),
+                    thread_name: labels::sanitize(&thread.name),
+                };
+
+                match self.previous.get(&thread_labels) {
+                    Some(previous) if thread.cpu_time >= *previous => {
+                        self.cpu_time
+                            .get_or_create(&thread_labels)
+                            .inc_by(thread.cpu_time - previous);
+                    }
+                    previous => {
+                        // Less CPU time than before means the TID has been reused
+                        if previous.is_some() {
+                            self.cpu_time.remove(&thread_labels);
+                        }
+                        self.cpu_time
+                            .get_or_create(&thread_labels)
+                            .inc_by(thread.cpu_time);
+                    }
+                }
+                current.insert(thread_labels, thread.cpu_time);
+            }
+        }
+
+        for thread_labels in self.previous.keys() {
+            if !current.contains_key(thread_labels) {
+                self.cpu_time.remove(thread_labels);
+            }
+        }
+        self.previous = current;
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{
+    labels::Labels,
+    metrics::{EnergyUnit, Sample},
+};
+use anyhow::{Context, Result};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Row, Table},
+    DefaultTerminal, Frame,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How often key presses are checked for.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Column the table is sorted by, in descending order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum SortBy {
+    #[default]
+    CpuUsage,
+    CpuTime,
+    Power,
+    Energy,
+}
+
+impl SortBy {
+    fn next(self) -> Self {
+        match self {
+            Self::CpuUsage => Self::CpuTime,
+            Self::CpuTime => Self::Power,
+            Self::Power => Self::Energy,
+            Self::Energy => Self::CpuUsage,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::CpuUsage => "CPU usage",
+            Self::CpuTime => "CPU time",
+            Self::Power => "power",
+            Self::Energy => "energy",
+        }
+    }
+
+    fn value(&self, sample: &Sample) -> f64 {
+        match self {
+            Self::CpuUsage => sample.cpu_usage.unwrap_or(0.0),
+            Self::CpuTime => sample.cpu_time,
+            Self::Power => sample.power.unwrap_or(0.0),
+            Self::Energy => sample.energy,
+        }
+    }
+}
+
+/// The label that best names a series, depending on how processes are grouped.
+fn series_name(labels: &Labels) -> &str {
+    [
+        &labels.group,
+        &labels.process_name,
+        &labels.systemd_unit,
+        &labels.cgroup,
+    ]
+    .into_iter()
+    .find_map(|label| label.as_deref())
+    .unwrap_or_default()
+}
+
+fn format_optional(value: Option<f64>, format: impl Fn(f64) -> String) -> String {
+    value.map(format).unwrap_or_else(|| "-".to_string())
+}
+
+struct SeriesTable {
+    energy_unit: EnergyUnit,
+    sort_by: SortBy,
+    series: Vec<(Labels, Sample)>,
+}
+
+impl SeriesTable {
+    fn draw(&mut self, frame: &mut Frame) {
+        let sort_by = self.sort_by;
+        self.series.sort_by(|(a_labels, a), (b_labels, b)| {
+            sort_by
+                .value(b)
+                .total_cmp(&sort_by.value(a))
+                .then_with(|| a_labels.pairs().cmp(&b_labels.pairs()))
+        });
+
+        let [title, table, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let count = self.series.len();
+        frame.render_widget(
+            Line::from(format!(
+                "yuugi: {} series, sorted by {}",
+                count,
+                sort_by.name()
+            ))
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+            title,
+        );
+
+        let header = Row::new([
+            "PID".to_string(),
+            "NAME".to_string(),
+            "USER".to_string(),
+            "CPU %".to_string(),
+            "CPU TIME".to_string(),
+            "POWER W".to_string(),
+            format!(
+                "ENERGY {}",
+                self.energy_unit.name().replace('_', " ").to_uppercase()
+            ),
+            "COMMAND".to_string(),
+        ])
+        .style(Style::new().add_modifier(Modifier::REVERSED));
+
+        let rows = self.series.iter().map(|(labels, sample)| {
+            Row::new([
+                labels.pid.clone().unwrap_or_default(),
+                series_name(labels).to_string(),
+                labels.user.clone().unwrap_or_default(),
+                format_optional(sample.cpu_usage, |v| format!("{:.1}", v * 100.0)),
+                format!("{:.2}", sample.cpu_time),
+                format_optional(sample.power, |v| format!("{:.2}", v)),
+                format!("{:.4}", sample.energy),
+                labels.cmdline.clone().unwrap_or_default(),
+            ])
+        });
+
+        frame.render_widget(
+            Table::new(
+                rows,
+                [
+                    Constraint::Length(8),
+                    Constraint::Length(20),
+                    Constraint::Length(10),
+                    Constraint::Length(6),
+                    Constraint::Length(10),
+                    Constraint::Length(8),
+                    Constraint::Length(20),
+                    Constraint::Fill(1),
+                ],
+            )
+            .header(header),
+            table,
+        );
+
+        frame.render_widget(
+            Line::from(format!("s: sort by {}, q: quit", sort_by.next().name())),
+            help,
+        );
+    }
+}
+
+/// Renders the series of every collection as a live table in the terminal, until the user quits.
+pub(crate) struct Top {
+    series: mpsc::Sender<Vec<(Labels, Sample)>>,
+    quit: tokio::sync::mpsc::Receiver<()>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Top {
+    /// Takes over the terminal, which is restored when dropped.
+    pub(crate) fn start(energy_unit: EnergyUnit) -> Result<Self> {
+        let (series_tx, series_rx) = mpsc::channel();
+        let (quit_tx, quit_rx) = tokio::sync::mpsc::channel(1);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let terminal = ratatui::try_init().context("Failed to initialise terminal")?;
+        let table = SeriesTable {
+            energy_unit,
+            sort_by: SortBy::default(),
+            series: Vec::new(),
+        };
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let result = run(terminal, table, series_rx, &stop);
+                ratatui::restore();
+                if let Err(e) = result {
+                    log::error!("{:#}", e);
+                }
+                quit_tx.try_send(()).ok();
+            })
+        };
+
+        Ok(Self {
+            series: series_tx,
+            quit: quit_rx,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Shows the series of a collection.
+    pub(crate) fn update(&self, series: Vec<(Labels, Sample)>) {
+        self.series.send(series).ok();
+    }
+}
+
+impl Drop for Top {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Waits for the user to quit, forever if there is no table.
+pub(crate) async fn quit(top: &mut Option<Top>) {
+    match top {
+        Some(top) => {
+            top.quit.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn run(
+    mut terminal: DefaultTerminal,
+    mut table: SeriesTable,
+    series: mpsc::Receiver<Vec<(Labels, Sample)>>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut redraw = true;
+
+    while !stop.load(Ordering::Relaxed) {
+        // Only the latest collection is shown
+        while let Ok(latest) = series.try_recv() {
+            table.series = latest;
+            redraw = true;
+        }
+
+        if redraw {
+            terminal
+                .draw(|frame| table.draw(frame))
+                .context("Failed to draw table")?;
+            redraw = false;
+        }
+
+        if event::poll(POLL_INTERVAL).context("Failed to read terminal events")? {
+            match event::read().context("Failed to read terminal events")? {
+                // Raw mode stops Ctrl-C from sending SIGINT
+                Event::Key(key) if key.kind == KeyEventKind::Press => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        return Ok(())
+                    }
+                    KeyCode::Char('s') => {
+                        table.sort_by = table.sort_by.next();
+                        redraw = true;
+                    }
+                    _ => {}
+                },
+                Event::Resize(_, _) => redraw = true,
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
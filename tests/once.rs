@@ -0,0 +1,26 @@
+//! Runs yuugi with --once, which can only be checked end to end.
+
+use std::process::Command;
+
+#[test]
+fn once_includes_cpu_usage() {
+    let output = Command::new(env!("CARGO_BIN_EXE_yuugi"))
+        .args([
+            "--once",
+            "--collection-interval",
+            "200ms",
+            "--energy-metric",
+            "both",
+        ])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert!(stdout.contains("\ncpu_usage_ratio{"), "{}", stdout);
+    assert!(stdout.contains("\npower_watts{"), "{}", stdout);
+}